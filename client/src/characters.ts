@@ -8,8 +8,15 @@ export class Character {
 
         this.hp = data.hp ?? null
         this.hp_max = data.hp_max ?? null
+        this.temp_hp = data.temp_hp ?? 0
         this.initiative = data.initiative ?? null
 
+        this.resistances = data.resistances ?? []
+        this.vulnerabilities = data.vulnerabilities ?? []
+        this.immunities = data.immunities ?? []
+
+        this.saving_throws = data.saving_throws ?? {}
+
         this.items = (data.items ?? []).map(v => new Item(v))
 
         this.spells = (data.spells ?? []).map(v => new Spell(v))
@@ -25,8 +32,16 @@ export class Character {
 
     hp_max: number | null
     hp: number | null
+    temp_hp: number
     initiative: number | null
 
+    resistances: string[]
+    vulnerabilities: string[]
+    immunities: string[]
+
+    // Saving throw modifiers by lowercase ability name, like `dexterity`
+    saving_throws: { [ability: string]: number }
+
     items: Item[]
 
     spells: Spell[]
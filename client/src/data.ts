@@ -3,20 +3,72 @@ import { Character } from "./characters"
 
 const urlParams = new URLSearchParams(location.search)
 
-let campaigns_raw = JSON.parse(
-    localStorage.getItem("campaigns_gming") ?? "{}"
-) as { [key: string]: string[] }
+/**
+ * Turn campaigns encoded as JSON back into characters
+ */
+export function parseCampaigns(raw: string): { [key: string]: Character[] } {
+    let campaigns_raw = JSON.parse(raw) as { [key: string]: string[] }
 
-let campaigns = {} as { [key: string]: Character[] }
+    let campaigns = {} as { [key: string]: Character[] }
 
-Object.keys(campaigns_raw).forEach(key => {
-    campaigns[key] = campaigns_raw[key].map(v => new Character(v))
-})
+    Object.keys(campaigns_raw).forEach(key => {
+        campaigns[key] = campaigns_raw[key].map(v => new Character(v))
+    })
+
+    return campaigns
+}
+
+export let CAMPAIGNS = new Store(
+    parseCampaigns(localStorage.getItem("campaigns_gming") ?? "{}"),
+    data => {
+        localStorage.setItem("campaigns_gming", JSON.stringify(data))
+        CAMPAIGNS_MODIFIED.set(Date.now())
+    }
+)
+
+let maybe_modified = parseInt(localStorage.getItem("campaigns_modified")!)
+
+// When the campaigns were last changed, in milliseconds since the unix epoch
+export let CAMPAIGNS_MODIFIED = new Store(
+    isNaN(maybe_modified) ? 0 : maybe_modified,
+    v => localStorage.setItem("campaigns_modified", v.toString())
+)
+
+let maybe_last_synced = parseInt(localStorage.getItem("campaigns_last_synced")!)
 
-export let CAMPAIGNS = new Store(campaigns, data =>
-    localStorage.setItem("campaigns_gming", JSON.stringify(data))
+// The modified time of the server's copy of the campaigns the last time they were synced
+export let CAMPAIGNS_LAST_SYNCED = new Store(
+    isNaN(maybe_last_synced) ? null : maybe_last_synced,
+    v => {
+        if (v === null) {
+            localStorage.removeItem("campaigns_last_synced")
+        } else {
+            localStorage.setItem("campaigns_last_synced", v.toString())
+        }
+    }
 )
 
+// The name the campaigns are synced to the server under, null if they've never been synced
+export let SYNC_IDENTITY = new Store(
+    localStorage.getItem("sync_identity"),
+    v => {
+        if (v === null) {
+            localStorage.removeItem("sync_identity")
+        } else {
+            localStorage.setItem("sync_identity", v)
+        }
+    }
+)
+
+// The name the server's GUI shows for this browser, null if it hasn't been set
+export let PLAYER_NAME = new Store(localStorage.getItem("player_name"), v => {
+    if (v === null) {
+        localStorage.removeItem("player_name")
+    } else {
+        localStorage.setItem("player_name", v)
+    }
+})
+
 let ip = sessionStorage.getItem("ip")
 
 export let IP_ADDRESS = new Store(ip === "" ? null : ip, data => {
@@ -31,6 +83,34 @@ export let IS_GM = new Store(sessionStorage.getItem("gm") === "true", v =>
     sessionStorage.setItem("gm", v === true ? "true" : "false")
 )
 
+export let IS_SPECTATOR = new Store(
+    sessionStorage.getItem("spectator") === "true",
+    v => sessionStorage.setItem("spectator", v === true ? "true" : "false")
+)
+
+let maybe_table_pin = parseInt(sessionStorage.getItem("table_pin")!)
+
+export let TABLE_PIN = new Store(
+    isNaN(maybe_table_pin) ? null : maybe_table_pin,
+    v => {
+        if (v === null) {
+            sessionStorage.removeItem("table_pin")
+        } else {
+            sessionStorage.setItem("table_pin", v.toString())
+        }
+    }
+)
+
+let maybe_gm_pin = parseInt(sessionStorage.getItem("gm_pin")!)
+
+export let GM_PIN = new Store(isNaN(maybe_gm_pin) ? null : maybe_gm_pin, v => {
+    if (v === null) {
+        sessionStorage.removeItem("gm_pin")
+    } else {
+        sessionStorage.setItem("gm_pin", v.toString())
+    }
+})
+
 let maybe_id = parseInt(sessionStorage.getItem("server_id")!)
 
 export let CLIENT_ID = new Store(isNaN(maybe_id) ? null : maybe_id, v => {
@@ -41,6 +121,14 @@ export let CLIENT_ID = new Store(isNaN(maybe_id) ? null : maybe_id, v => {
     }
 })
 
+export let CLIENT_TOKEN = new Store(sessionStorage.getItem("server_token"), v => {
+    if (v === null) {
+        sessionStorage.removeItem("server_token")
+    } else {
+        sessionStorage.setItem("server_token", v)
+    }
+})
+
 export let CAMPAIGN_NAME = urlParams.get("campaign") ?? null
 
 export let CHARACTER_NAME = urlParams.get("character") ?? null
@@ -1,27 +1,103 @@
 import { Sendable, strats } from "triangulum"
 import type { Character } from "./characters"
+import { IS_GM } from "./data"
 import { Message } from "./socket"
 
 @Message(
     "Id",
     strats.class({
         id: strats.isNumber,
+        token: strats.isString,
     })
 )
 export class Id extends Sendable {
-    constructor(id: number) {
+    constructor(
+        id: number,
+        token: string,
+        pin: number | null = null,
+        name: string | null = null
+    ) {
         super()
         this.id = id
+        this.token = token
+        this.pin = pin
+        this.name = name
     }
 
     id: number
+
+    // The secret that proves the id is ours, ids on their own are shown to everyone
+    token: string
+
+    // The table PIN, if the GM set one
+    pin: number | null
+
+    // The name the server's GUI shows for this player
+    name: string | null
 }
 
 @Message("RequestId", strats.dontCheck())
 export class RequestId extends Sendable {
-    constructor() {
+    constructor(pin: number | null = null, name: string | null = null) {
+        super()
+        this.pin = pin
+        this.name = name
+    }
+
+    pin: number | null
+    name: string | null
+}
+
+@Message("Spectate", strats.dontCheck())
+export class Spectate extends Sendable {
+    constructor(pin: number | null = null, name: string | null = null) {
+        super()
+        this.pin = pin
+        this.name = name
+    }
+
+    pin: number | null
+    name: string | null
+}
+
+@Message("JoinRoom", strats.dontCheck())
+export class JoinRoom extends Sendable {
+    constructor(campaign: string) {
         super()
+        this.campaign = campaign
     }
+
+    campaign: string
+}
+
+@Message("ClaimGm", strats.dontCheck())
+export class ClaimGm extends Sendable {
+    constructor(pin: number) {
+        super()
+        this.pin = pin
+    }
+
+    pin: number
+}
+
+@Message(
+    "Role",
+    strats.class({
+        role: strats.isString,
+    })
+)
+export class Role extends Sendable {
+    role: "Player" | "Gm" | "Spectator"
+}
+
+@Message(
+    "Error",
+    strats.class({
+        message: strats.isString,
+    })
+)
+export class ServerError extends Sendable {
+    message: string
 }
 
 @Message(
@@ -39,4 +115,162 @@ export class CharacterUpdated extends Sendable {
 
     data: string
     player_id: number | undefined = undefined
+
+    // Set by the server when it changed the character itself, so the owner has to update their copy
+    overwrite: boolean | undefined = undefined
+
+    // NPCs the GM makes start out hidden so they can be revealed later
+    visibility: "Hidden" | "Revealed" | "Redacted" | undefined = IS_GM.value
+        ? "Hidden"
+        : undefined
+}
+
+@Message(
+    "CharacterRemoved",
+    strats.class({
+        name: strats.isString,
+    })
+)
+export class CharacterRemoved extends Sendable {
+    constructor(name: string) {
+        super()
+        this.name = name
+    }
+
+    name: string
+}
+
+export interface SentCharacter {
+    data: string
+    player_id: number
+}
+
+// Every character we can see, any others we have were removed
+@Message("Characters", strats.dontCheck())
+export class Characters extends Sendable {
+    characters: SentCharacter[]
+
+    // Set when we might've missed changes the server made to our own characters
+    overwrite: boolean
+}
+
+@Message("UploadCampaigns", strats.dontCheck())
+export class UploadCampaigns extends Sendable {
+    constructor(
+        identity: string,
+        passphrase: string,
+        campaigns: string,
+        modified: number,
+        last_synced: number | null,
+        force: boolean
+    ) {
+        super()
+        this.identity = identity
+        this.passphrase = passphrase
+        this.campaigns = campaigns
+        this.modified = modified
+        this.last_synced = last_synced
+        this.force = force
+    }
+
+    identity: string
+    passphrase: string
+    campaigns: string
+    modified: number
+
+    // The server's modified time the last time this browser synced, so the server can tell if both copies changed
+    last_synced: number | null
+
+    force: boolean
+}
+
+@Message("DownloadCampaigns", strats.dontCheck())
+export class DownloadCampaigns extends Sendable {
+    constructor(identity: string, passphrase: string) {
+        super()
+        this.identity = identity
+        this.passphrase = passphrase
+    }
+
+    identity: string
+    passphrase: string
+}
+
+@Message(
+    "CampaignsUploaded",
+    strats.class({
+        modified: strats.isNumber,
+    })
+)
+export class CampaignsUploaded extends Sendable {
+    modified: number
+}
+
+@Message(
+    "CampaignsDownloaded",
+    strats.class({
+        campaigns: strats.isString,
+        modified: strats.isNumber,
+    })
+)
+export class CampaignsDownloaded extends Sendable {
+    campaigns: string
+    modified: number
+}
+
+@Message(
+    "SyncConflict",
+    strats.class({
+        server_modified: strats.isNumber,
+    })
+)
+export class SyncConflict extends Sendable {
+    server_modified: number
+}
+
+@Message("GetHistory", strats.dontCheck())
+export class GetHistory extends Sendable {
+    constructor(character: string) {
+        super()
+        this.character = character
+    }
+
+    character: string
+}
+
+@Message("UndoTo", strats.dontCheck())
+export class UndoTo extends Sendable {
+    constructor(character: string, version: number) {
+        super()
+        this.character = character
+        this.version = version
+    }
+
+    character: string
+    version: number
+}
+
+export interface CharacterVersion {
+    version: number
+
+    // Seconds since the unix epoch
+    timestamp: number
+
+    // The id of whoever made the change
+    author: number
+
+    data: string
+}
+
+@Message(
+    "History",
+    strats.class({
+        character: strats.isString,
+    })
+)
+export class History extends Sendable {
+    character: string
+
+    // Newest first
+    versions: CharacterVersion[]
 }
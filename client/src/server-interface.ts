@@ -1,11 +1,50 @@
 import { ProceduralStore, Store } from "./better-store"
-import { CAMPAIGNS, CAMPAIGN_NAME, IP_ADDRESS, CLIENT_ID } from "./data"
+import {
+    CAMPAIGNS,
+    CAMPAIGN_NAME,
+    IP_ADDRESS,
+    CLIENT_ID,
+    CLIENT_TOKEN,
+    GM_PIN,
+    IS_GM,
+    IS_SPECTATOR,
+    PLAYER_NAME,
+    TABLE_PIN,
+} from "./data"
 import { Character } from "./characters"
 import { ConnectionManager } from "./socket"
-import { CharacterUpdated, Id, RequestId } from "./sendable-types"
+import {
+    CharacterRemoved,
+    Characters,
+    CharacterUpdated,
+    ClaimGm,
+    GetHistory,
+    History,
+    Id,
+    JoinRoom,
+    RequestId,
+    Role,
+    ServerError,
+    Spectate,
+    UndoTo,
+} from "./sendable-types"
+import { listenForSync } from "./sync"
 
 export let socket: ConnectionManager | null = null
 
+/** How long to wait before reconnecting after the connection drops, in milliseconds */
+const RECONNECT_DELAY = 2000
+
+/** How many times in a row to try reconnecting before giving up */
+const MAX_RECONNECT_ATTEMPTS = 10
+
+/** The close code the server uses when the GM kicks or bans us */
+const KICKED_CLOSE_CODE = 4000
+
+let reconnectAttempts = 0
+
+let stopSendingCharacters: (() => void) | null = null
+
 export function connect() {
     console.log("Trying to connect")
 
@@ -16,14 +55,66 @@ export function connect() {
         return
     }
 
-    if (CLIENT_ID.value === null) {
-        socket.send(new RequestId())
+    let opened = false
+
+    socket.ws.addEventListener("open", () => {
+        opened = true
+        reconnectAttempts = 0
+    })
+
+    // The server keeps our characters around for a while, so reconnecting with the same id picks up where we left off
+    socket.ws.addEventListener("close", event => {
+        // Never connecting in the first place means the address is wrong, rather than the server restarting
+        let neverConnected = !opened && reconnectAttempts === 0
+
+        // Coming straight back after being kicked would defeat the point
+        let kicked = event.code === KICKED_CLOSE_CODE
+
+        if (
+            neverConnected ||
+            kicked ||
+            reconnectAttempts >= MAX_RECONNECT_ATTEMPTS
+        ) {
+            reconnectAttempts = 0
+            socket?.disconnect()
+            socket = null
+            characterList.nextValueAvailable()
+            return
+        }
+
+        reconnectAttempts++
+        setTimeout(connect, RECONNECT_DELAY)
+    })
+
+    if (IS_SPECTATOR.value) {
+        socket.send(new Spectate(TABLE_PIN.value, PLAYER_NAME.value))
+    } else if (CLIENT_ID.value === null || CLIENT_TOKEN.value === null) {
+        socket.send(new RequestId(TABLE_PIN.value, PLAYER_NAME.value))
     } else {
-        socket.send(new Id(CLIENT_ID.value))
+        socket.send(
+            new Id(
+                CLIENT_ID.value,
+                CLIENT_TOKEN.value,
+                TABLE_PIN.value,
+                PLAYER_NAME.value
+            )
+        )
+    }
+
+    // Each campaign is its own room on the server, the GM role is claimed per room so it has to come after joining
+    if (CAMPAIGN_NAME !== null) {
+        socket.send(new JoinRoom(CAMPAIGN_NAME))
+    }
+
+    if (IS_GM.value && !IS_SPECTATOR.value && GM_PIN.value !== null) {
+        socket.send(new ClaimGm(GM_PIN.value))
     }
 
-    CLIENT_ID.subscribe(id => {
-        if (CAMPAIGN_NAME !== null && id !== null) {
+    stopSendingCharacters?.()
+
+    stopSendingCharacters = CLIENT_ID.subscribe(id => {
+        // Spectators can't own characters, so there's nothing to send
+        if (CAMPAIGN_NAME !== null && id !== null && !IS_SPECTATOR.value) {
             CAMPAIGNS.value[CAMPAIGN_NAME].forEach(character => {
                 character.owner = id
                 socket!.send(new CharacterUpdated(character))
@@ -32,11 +123,31 @@ export function connect() {
     })
 
     socket.listen(Id, id => {
+        // The token has to be stored first, since setting the id sends our characters
+        CLIENT_TOKEN.set(id.token)
         CLIENT_ID.set(id.id)
     })
 
     socket.listen(CharacterUpdated, onCharacterUpdated)
 
+    socket.listen(CharacterRemoved, onCharacterRemoved)
+
+    socket.listen(Characters, onCharacters)
+
+    socket.listen(Role, role => {
+        IS_GM.set(role.role === "Gm")
+    })
+
+    socket.listen(ServerError, error => {
+        alert(error.message)
+    })
+
+    socket.listen(History, history => {
+        characterHistory.set(history)
+    })
+
+    listenForSync(socket)
+
     characterList.nextValueAvailable()
 }
 
@@ -103,12 +214,51 @@ export let characterList: CharacterList = new CharacterList()
 
 function onCharacterUpdated(characterUpdated: CharacterUpdated) {
     console.log(characterUpdated)
-    if (characterUpdated.player_id === CLIENT_ID.value) {
+
+    characterReceived(
+        characterUpdated.data,
+        characterUpdated.player_id,
+        characterUpdated.overwrite ?? false
+    )
+}
+
+/**
+ * Replace every character from the server, so ones removed while we weren't keeping up go away too
+ */
+function onCharacters(characters: Characters) {
+    let decoded = characters.characters.map(
+        v => new Character(JSON.parse(v.data))
+    )
+
+    networkCharacters.update(stores =>
+        stores.filter(store =>
+            decoded.some(character => character.name === store.value.name)
+        )
+    )
+
+    characters.characters.forEach(v =>
+        characterReceived(v.data, v.player_id, characters.overwrite)
+    )
+
+    characterList.nextValueAvailable()
+}
+
+function characterReceived(
+    data: string,
+    playerId: number | undefined,
+    overwrite: boolean
+) {
+    let decoded = new Character(JSON.parse(data))
+
+    // Characters the server made for us, like NPCs spawned from a template, aren't stored locally so they're treated like anyone else's
+    if (playerId === CLIENT_ID.value && isLocalCharacter(decoded.name)) {
+        if (overwrite) {
+            overwriteLocalCharacter(decoded)
+        }
+
         return
     }
 
-    let decoded = new Character(JSON.parse(characterUpdated.data))
-
     let indexOfCharacter = networkCharacters.value.findIndex(
         v => v.value.name === decoded.name
     )
@@ -134,6 +284,72 @@ function onCharacterUpdated(characterUpdated: CharacterUpdated) {
     characterList.nextValueAvailable()
 }
 
+/**
+ * The versions of the character whose history was asked for most recently
+ */
+export let characterHistory: Store<History | null> = new Store(null)
+
+/**
+ * Ask the server for a character's recent versions, they end up in `characterHistory`
+ */
+export function getHistory(character: string) {
+    socket?.send(new GetHistory(character))
+}
+
+/**
+ * Put a character back the way it was in an old version
+ */
+export function undoTo(character: string, version: number) {
+    socket?.send(new UndoTo(character, version))
+}
+
+/**
+ * Become the GM, if the PIN is the one shown on the server
+ */
+export function claimGm(pin: number) {
+    GM_PIN.set(pin)
+
+    socket?.send(new ClaimGm(pin))
+}
+
+function onCharacterRemoved(characterRemoved: CharacterRemoved) {
+    networkCharacters.update(characters =>
+        characters.filter(v => v.value.name !== characterRemoved.name)
+    )
+
+    characterList.nextValueAvailable()
+}
+
+function isLocalCharacter(name: string): boolean {
+    return (
+        CAMPAIGN_NAME !== null &&
+        (CAMPAIGNS.value[CAMPAIGN_NAME] ?? []).some(v => v.name === name)
+    )
+}
+
+/**
+ * Replace a local character's data with data the server changed, like after the GM applies damage
+ */
+function overwriteLocalCharacter(character: Character) {
+    if (CAMPAIGN_NAME === null) {
+        return
+    }
+
+    let local = CAMPAIGNS.value[CAMPAIGN_NAME]?.find(
+        v => v.name === character.name
+    )
+
+    if (local === undefined) {
+        return
+    }
+
+    // Mutate the existing object so stores wrapping it see the new data
+    Object.assign(local, character)
+
+    CAMPAIGNS.notifySubscribers()
+    characterList.nextValueAvailable()
+}
+
 if (IP_ADDRESS.value) {
     connect()
 }
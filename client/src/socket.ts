@@ -10,6 +10,29 @@ const messageRegistry = new Registry<Sendable>()
 
 export const Message = makeCustomSendableDecorator(messageRegistry)
 
+/**
+ * The websocket URL for the address the player typed in, which can be the URL
+ * the server shows like `http://192.168.1.2:8000` or just `host:port`
+ *
+ * The server's port can be changed, so leaving it out uses the scheme's default.
+ * IPv6 addresses like `fe80::1` get the brackets URLs need around them
+ */
+export function websocketUrl(address: string): string {
+    let [, scheme = "http://", host = ""] =
+        /^([a-z][a-z0-9+.-]*:\/\/)?(.*)$/i.exec(address.trim())!
+
+    // More than one colon can only be an IPv6 address, which can't have a port without brackets
+    if (!host.startsWith("[") && (host.match(/:/g) ?? []).length > 1) {
+        host = `[${host.replace(/\/+$/, "")}]`
+    }
+
+    let url = new URL(scheme + host)
+
+    let secure = url.protocol === "https:" || url.protocol === "wss:"
+
+    return `${secure ? "wss" : "ws"}://${url.host}`
+}
+
 export class ConnectionManager extends AbstractListenerManager<
     Sendable,
     object,
@@ -18,11 +41,7 @@ export class ConnectionManager extends AbstractListenerManager<
     constructor() {
         super(messageRegistry)
 
-        let address = `ws://${IP_ADDRESS.value?.trim()}${
-            IP_ADDRESS.value!.includes(":") ? "" : ":8000"
-        }`
-
-        this.ws = new WebSocket(address)
+        this.ws = new WebSocket(websocketUrl(IP_ADDRESS.value!))
 
         this.ws.onmessage = e => {
             if (typeof e.data !== "string") return
@@ -33,8 +52,6 @@ export class ConnectionManager extends AbstractListenerManager<
         this.ws.onopen = e => {
             this.ready()
         }
-
-        this.ws.onerror = this.disconnect
     }
 
     disconnect() {
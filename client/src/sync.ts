@@ -0,0 +1,118 @@
+import {
+    CAMPAIGNS,
+    CAMPAIGNS_LAST_SYNCED,
+    CAMPAIGNS_MODIFIED,
+    parseCampaigns,
+    SYNC_IDENTITY,
+} from "./data"
+import {
+    CampaignsDownloaded,
+    CampaignsUploaded,
+    DownloadCampaigns,
+    SyncConflict,
+    UploadCampaigns,
+} from "./sendable-types"
+import { socket } from "./server-interface"
+import type { ConnectionManager } from "./socket"
+
+/*
+ * Campaigns can be stored on the server under a name & passphrase, so they can be restored from any browser.
+ * Nothing gets synced unless the player asks for it.
+ */
+
+// Kept around so resolving a conflict doesn't ask for the passphrase again
+let passphrase: string | null = null
+
+/**
+ * Ask for the name & passphrase to sync under, returns false if the player cancelled
+ */
+function askForIdentity(): boolean {
+    let identity = prompt(
+        "The name to sync your campaigns under",
+        SYNC_IDENTITY.value ?? ""
+    )
+
+    if (identity === null || identity === "") {
+        return false
+    }
+
+    let newPassphrase = prompt("Passphrase")
+
+    if (newPassphrase === null) {
+        return false
+    }
+
+    SYNC_IDENTITY.set(identity)
+    passphrase = newPassphrase
+
+    return true
+}
+
+/**
+ * Store this browser's campaigns on the server
+ *
+ * Unless `force` is set, the server refuses if its copy changed since this browser last synced
+ */
+export function uploadCampaigns(force = false) {
+    if (socket === null || (!force && !askForIdentity())) {
+        return
+    }
+
+    socket.send(
+        new UploadCampaigns(
+            SYNC_IDENTITY.value!,
+            passphrase!,
+            JSON.stringify(CAMPAIGNS.value),
+            CAMPAIGNS_MODIFIED.value,
+            CAMPAIGNS_LAST_SYNCED.value,
+            force
+        )
+    )
+}
+
+/**
+ * Replace this browser's campaigns with the ones stored on the server
+ */
+export function downloadCampaigns(askFirst = true) {
+    if (socket === null || (askFirst && !askForIdentity())) {
+        return
+    }
+
+    socket.send(new DownloadCampaigns(SYNC_IDENTITY.value!, passphrase!))
+}
+
+/**
+ * Handle the server's responses to syncing
+ */
+export function listenForSync(socket: ConnectionManager) {
+    socket.listen(CampaignsUploaded, uploaded => {
+        CAMPAIGNS_LAST_SYNCED.set(uploaded.modified)
+        alert("Your campaigns are synced to the server")
+    })
+
+    socket.listen(CampaignsDownloaded, downloaded => {
+        CAMPAIGNS.set(parseCampaigns(downloaded.campaigns))
+
+        // Setting the campaigns marks them as changed, but they're the same as the server's copy
+        CAMPAIGNS_MODIFIED.set(downloaded.modified)
+        CAMPAIGNS_LAST_SYNCED.set(downloaded.modified)
+
+        location.reload()
+    })
+
+    socket.listen(SyncConflict, conflict => {
+        let serverChanged = new Date(conflict.server_modified).toLocaleString()
+        let localChanged = new Date(CAMPAIGNS_MODIFIED.value).toLocaleString()
+
+        let keepLocal = confirm(
+            `Your campaigns changed on the server (${serverChanged}) and in this browser (${localChanged}) since they were last synced.\n\n` +
+                "OK replaces the server's copy with this browser's, Cancel replaces this browser's copy with the server's."
+        )
+
+        if (keepLocal) {
+            uploadCampaigns(true)
+        } else {
+            downloadCampaigns(false)
+        }
+    })
+}
@@ -1,13 +1,27 @@
-use std::sync::Arc;
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    process,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use iced::{executor, Align, Application, Clipboard, Color, Column, Command, Length, Subscription};
 use iced_native::widget::*;
 
-use crate::server::{Server, ServerCommand, ServerMessage, ServerStatus};
+use server::{
+    format_pin, CharacterSummary, Config, ConnectionInfo, Port, Role, RunningInstance, Server,
+    ServerCommand, ServerError, ServerMessage, ServerStatus, PIN_DIGITS,
+};
+use tokio::sync::broadcast;
+use tracing::Level;
 
 use self::InputChanged::*;
-use crate::server::ServerStatus::*;
+use crate::logging::LogEntry;
 use crate::styling::{self, PADDING};
+use crate::subscription;
+use server::ServerStatus::*;
 use Message::*;
 use ServerCommand::*;
 
@@ -15,20 +29,149 @@ pub struct Gui {
     server_status: ServerStatus,
     server: Server,
     widgets: Widgets,
+    pin_widgets: PinWidgets,
+    address_widgets: AddressWidgets,
     connections: Vec<ConnectionData>,
+
+    /// Connections that closed within the reconnect grace period, most recent last
+    recently_left: Vec<ConnectionData>,
+
+    /// The name of every room and how many people are in it
+    rooms: Vec<(String, usize)>,
+
+    /// Every character in every room, for the party dashboard
+    characters: Vec<CharacterSummary>,
+    party_widgets: PartyWidgets,
+
+    /// How the last backup export or import went
+    backup_message: Option<String>,
+
+    /// The settings the app was started with, along with any changes made since
+    config: Config,
+
+    /// Everything that's been logged, newest first
+    logs: VecDeque<LogEntry>,
+    logs_sender: broadcast::Sender<LogEntry>,
+    log_widgets: LogWidgets,
+
+    /// Another copy of the server that was already running when the app opened
+    running: Option<RunningInstance>,
+    running_widgets: RunningWidgets,
+
+    /// Set when the app should close
+    exit: bool,
 }
 
+/// What the GUI needs to start
+pub struct GuiFlags {
+    pub config: Config,
+
+    /// Sends everything that's logged, to be shown in the log panel
+    pub logs: broadcast::Sender<LogEntry>,
+
+    /// Another copy of the server that's already running, if there is one
+    pub running: Option<RunningInstance>,
+}
+
+/// How many log messages are kept for the log panel, older ones are dropped
+const MAX_LOGS: usize = 500;
+
 #[derive(Default)]
 struct Widgets {
     restart_server: button::State,
     stop_server: button::State,
     port: text_input::State,
     port_number: String,
+    autostart: button::State,
+    use_free_port: button::State,
+    export_backup: button::State,
+    import_backup: button::State,
+}
+
+/// The widgets for the log panel
+struct LogWidgets {
+    scroll: scrollable::State,
+    level: button::State,
+
+    /// The least important kind of message that's shown
+    shown_level: Level,
+}
+
+impl Default for LogWidgets {
+    fn default() -> LogWidgets {
+        LogWidgets {
+            scroll: scrollable::State::default(),
+            level: button::State::default(),
+            shown_level: Level::INFO,
+        }
+    }
+}
+
+/// The buttons offered when another copy of the server is already running
+#[derive(Default)]
+struct RunningWidgets {
+    open: button::State,
+    quit: button::State,
+}
+
+/// The addresses the server can listen on, separate from `Widgets` since they're in a different row
+struct AddressWidgets {
+    options: Vec<AddressOption>,
+    new_address: text_input::State,
+    new_address_text: String,
+}
+
+struct AddressOption {
+    address: IpAddr,
+    toggle: button::State,
+}
+
+/// The filter & sort buttons for the party dashboard
+#[derive(Default)]
+struct PartyWidgets {
+    filter: text_input::State,
+    filter_text: String,
+    sort_buttons: [button::State; 5],
+    sort: PartySort,
+
+    /// Whether the sort is flipped from the order it starts in
+    reversed: bool,
+}
+
+/// What the party dashboard can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PartySort {
+    #[default]
+    Name,
+    Owner,
+    Hp,
+    Initiative,
+    Weight,
+}
+
+/// The widgets for the PINs, separate from `Widgets` since they're in a different row
+#[derive(Default)]
+struct PinWidgets {
+    table_pin: text_input::State,
+    table_pin_number: String,
 }
 
 #[derive(Debug, Clone)]
 pub enum InputChanged {
     PortNumber(String),
+    TablePin(String),
+    Autostart(bool),
+    LogLevel(Level),
+
+    /// Start or stop listening on an address
+    Listen(IpAddr, bool),
+    NewAddress(String),
+
+    /// Only show characters whose name, owner or room contains the text
+    PartyFilter(String),
+
+    /// Sort the party dashboard, or reverse it if it's already sorted that way
+    SortParty(PartySort),
 }
 
 #[derive(Debug, Clone)]
@@ -46,14 +189,44 @@ pub enum Message {
 
     /// A message was received from the server
     ServerMessage(ServerMessage),
+
+    /// Something was logged
+    Log(LogEntry),
+
+    /// Sent every second, to keep times up to date
+    Tick(Instant),
+
+    /// Switch to a port that's free and start the server on it, when the one that was picked is busy
+    UsePort(u16),
+
+    /// Add the address that was typed in to the addresses the server can listen on
+    AddAddress,
+
+    /// Switch to the port that was typed in
+    SubmitPort,
+
+    /// Require the table PIN that was typed in, or no PIN if it's empty
+    SubmitTablePin,
+
+    /// Open the server that was already running in the browser
+    OpenRunning,
+
+    /// Close the app
+    Quit,
 }
 
 impl Application for Gui {
     type Message = Message;
     type Executor = executor::Default;
-    type Flags = ();
+    type Flags = GuiFlags;
 
-    fn new(_flags: ()) -> (Gui, Command<Message>) {
+    fn new(
+        GuiFlags {
+            config,
+            logs,
+            running,
+        }: GuiFlags,
+    ) -> (Gui, Command<Message>) {
         let runtime = Arc::new(
             tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
@@ -64,9 +237,34 @@ impl Application for Gui {
         (
             Gui {
                 server_status: ServerStatus::Offline,
-                server: Server::new(Arc::clone(&runtime)),
-                widgets: Widgets::default(),
+                // Starting straight away would only fail if there's already a server using the same data
+                server: Server::new(
+                    Arc::clone(&runtime),
+                    Config {
+                        autostart: config.autostart && running.is_none(),
+                        ..config.clone()
+                    },
+                    None,
+                ),
+                widgets: Widgets {
+                    port_number: port_text(config.port),
+                    ..Widgets::default()
+                },
+                pin_widgets: PinWidgets::default(),
+                address_widgets: AddressWidgets::new(&config.bind),
                 connections: Vec::new(),
+                recently_left: Vec::new(),
+                rooms: Vec::new(),
+                characters: Vec::new(),
+                party_widgets: PartyWidgets::default(),
+                backup_message: None,
+                config,
+                logs: VecDeque::new(),
+                logs_sender: logs,
+                log_widgets: LogWidgets::default(),
+                running,
+                running_widgets: RunningWidgets::default(),
+                exit: false,
             },
             Command::none(),
         )
@@ -74,7 +272,15 @@ impl Application for Gui {
 
     /// Subscribe to the server's status
     fn subscription(&self) -> Subscription<Self::Message> {
-        self.server.subscription()
+        Subscription::batch(vec![
+            subscription::subscribe(&self.server),
+            subscription::subscribe_to_logs(&self.logs_sender),
+            iced::time::every(Duration::from_secs(1)).map(Tick),
+        ])
+    }
+
+    fn should_exit(&self) -> bool {
+        self.exit
     }
 
     fn title(&self) -> String {
@@ -93,33 +299,146 @@ impl Application for Gui {
 
             InputChanged(input) => match input {
                 PortNumber(number) => {
-                    self.server.send(SwitchPort {
-                        port: number.parse().unwrap_or(8000),
-                    });
-
                     self.widgets.port_number = number;
                 }
+
+                Autostart(autostart) => {
+                    self.change_config(|config| config.autostart = autostart);
+                }
+
+                LogLevel(level) => {
+                    self.log_widgets.shown_level = level;
+                }
+
+                Listen(address, listen) => self.listen(address, listen),
+
+                NewAddress(text) => {
+                    self.address_widgets.new_address_text = text;
+                }
+
+                PartyFilter(text) => {
+                    self.party_widgets.filter_text = text;
+                }
+
+                SortParty(sort) => {
+                    let widgets = &mut self.party_widgets;
+
+                    widgets.reversed = widgets.sort == sort && !widgets.reversed;
+                    widgets.sort = sort;
+                }
+
+                TablePin(pin) => {
+                    self.pin_widgets.table_pin_number = pin;
+                }
             },
 
             ServerMessage(msg) => match msg {
-                ServerMessage::NewConnection { id } => {
-                    let data = ConnectionData { id, shown: true };
+                ServerMessage::NewConnection { connection } => {
+                    // They came back, so they haven't really left
+                    self.recently_left
+                        .retain(|v| v.info.player != connection.player);
+
+                    self.update_connection(connection);
+                }
 
-                    match self.connections.iter().position(|v| v.id == id) {
-                        Some(position) => self.connections[position] = data,
-                        None => self.connections.push(data),
-                    };
+                ServerMessage::ConnectionChanged { connection } => {
+                    self.update_connection(connection);
                 }
 
                 ServerMessage::ClosedConnection { id } => {
-                    if let Some(position) = self.connections.iter().position(|v| v.id == id) {
-                        self.connections[position].shown = false;
+                    if let Some(position) = self.connections.iter().position(|v| v.info.id == id) {
+                        let mut data = self.connections.remove(position);
+                        data.left = Some(Instant::now());
+
+                        self.recently_left.push(data);
                     }
                 }
 
+                ServerMessage::RoomsChanged { rooms } => {
+                    self.rooms = rooms;
+                }
+
+                ServerMessage::BackupFinished { message } => {
+                    self.backup_message = Some(message);
+                }
+
+                ServerMessage::CharactersChanged { characters } => {
+                    self.characters = characters;
+                }
+
                 ServerMessage::Status(_) => unreachable!(), // Status messages are intercepted and sent as ServerStatus instead
             },
 
+            UsePort(port) => {
+                self.server.send(SwitchPort {
+                    port: Port::Fixed(port),
+                });
+                self.server.send(Restart);
+                self.change_config(|config| config.port = Port::Fixed(port));
+
+                self.widgets.port_number = port.to_string();
+            }
+
+            Tick(now) => {
+                // Once the grace period's over their characters are gone, so there's nothing to come back to
+                let grace_period = self.config.grace_periods.reconnect();
+
+                self.recently_left.retain(|v| {
+                    v.left
+                        .is_some_and(|left| now.duration_since(left) < grace_period)
+                });
+            }
+
+            Log(entry) => {
+                self.logs.push_front(entry);
+                self.logs.truncate(MAX_LOGS);
+            }
+
+            SubmitTablePin => {
+                self.server.send(SetTablePin {
+                    pin: self.pin_widgets.table_pin_number.parse().ok(),
+                });
+            }
+
+            SubmitPort => {
+                // Leaving the port empty lets the server pick one
+                let port = match self.widgets.port_number.as_str() {
+                    "" => Some(Port::Auto),
+                    number => number.parse().ok().filter(|&v| v != 0).map(Port::Fixed),
+                };
+
+                match port {
+                    Some(port) if port != self.config.port => {
+                        self.server.send(SwitchPort { port });
+                        self.change_config(|config| config.port = port);
+                    }
+
+                    Some(_) => {}
+
+                    // Put back the port that's being used, so the input doesn't show one that isn't
+                    None => self.widgets.port_number = port_text(self.config.port),
+                }
+            }
+
+            AddAddress => {
+                if let Ok(address) = self.address_widgets.new_address_text.trim().parse() {
+                    self.address_widgets.add(address);
+                    self.listen(address, true);
+
+                    self.address_widgets.new_address_text.clear();
+                }
+            }
+
+            OpenRunning => {
+                if let Some(instance) = self.running {
+                    open_in_browser(&instance.url());
+                }
+            }
+
+            Quit => {
+                self.exit = true;
+            }
+
             DoNothing => {}
         }
 
@@ -127,16 +446,40 @@ impl Application for Gui {
     }
 
     fn view(&mut self) -> iced::Element<'_, Self::Message> {
-        Column::with_children(vec![
+        // Connections that left are still worth naming, their characters stick around for a while
+        let owners: HashMap<u32, String> = self
+            .recently_left
+            .iter()
+            .chain(self.connections.iter())
+            .filter_map(|v| Some((v.info.player, v.info.name.clone()?)))
+            .collect();
+
+        // Both lists have buttons, so they each need their own mutable half of the connections
+        let (spectators, players): (Vec<_>, Vec<_>) = self
+            .connections
+            .iter_mut()
+            .partition(|v| v.info.role == Role::Spectator);
+
+        let mut children = vec![
             // Display the server status
             Text::new(format!(
                 "Server status: {}",
                 match &self.server_status {
                     Offline => "Offline".to_owned(),
                     Restarting => "Restarting".to_owned(),
-                    OnlineNoIp => "Online, couldn't get local IP address".to_owned(),
-                    Online { ip } => format!("Online, your local IP address is {}", ip),
-                    Error => "The server threw an error".to_owned(),
+                    Online { port, .. } | OnlineNoIp { port, .. } => {
+                        let urls = self.server_status.urls();
+
+                        if urls.is_empty() {
+                            format!("Online on port {}, couldn't get local IP address", port)
+                        } else {
+                            format!("Online, players can connect at {}", urls.join(" or "))
+                        }
+                    }
+                    Error(error) => match error.suggestion() {
+                        Some(suggestion) => format!("{}. {}", error, suggestion),
+                        None => error.to_string(),
+                    },
                 }
             ))
             .color(Color::WHITE)
@@ -144,27 +487,113 @@ impl Application for Gui {
             // The row of server interactions
             Row::with_children(Gui::server_interactions(
                 &mut self.widgets,
-                self.server_status,
+                &self.server_status,
+                self.config.autostart,
+            ))
+            .spacing(16)
+            .into(),
+            // The addresses the server listens on
+            Row::with_children(Gui::address_interactions(
+                &mut self.address_widgets,
+                &self.config.bind,
             ))
             .spacing(16)
+            .align_items(Align::Center)
             .into(),
+            // The PINs players enter to join & to become the GM
+            Row::with_children(Gui::pin_interactions(
+                &mut self.pin_widgets,
+                self.server.gm_pin(),
+            ))
+            .spacing(16)
+            .align_items(Align::Center)
+            .into(),
+            Text::new(self.backup_message.as_deref().unwrap_or(""))
+                .color(Color::WHITE)
+                .into(),
             Space::new(Length::Units(0), Length::Units(8)).into(),
-            Text::new("Connections").color(Color::WHITE).size(30).into(),
+            Text::new("Rooms").color(Color::WHITE).size(30).into(),
             Column::with_children(
-                self.connections
+                self.rooms
                     .iter()
-                    .filter(|v| v.shown)
-                    .map(|v| Row::with_children(v.view()).into())
+                    .map(|(name, members)| {
+                        Text::new(format!(
+                            "{}: {} {}",
+                            name,
+                            members,
+                            if *members == 1 { "member" } else { "members" }
+                        ))
+                        .color(Color::WHITE)
+                        .into()
+                    })
                     .collect::<Vec<_>>(),
             )
             .into(),
-        ])
-        .spacing(16)
-        .align_items(Align::Center)
-        .width(Length::Fill)
-        .height(Length::Fill)
-        .padding(16)
-        .into()
+            Text::new("Party").color(Color::WHITE).size(30).into(),
+            Gui::party_dashboard(&mut self.party_widgets, &self.characters, &owners),
+            Text::new("Connections").color(Color::WHITE).size(30).into(),
+            Column::with_children(
+                players
+                    .into_iter()
+                    .map(|v| {
+                        Row::with_children(v.view())
+                            .spacing(16)
+                            .align_items(Align::Center)
+                            .into()
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .into(),
+            Text::new("Spectators").color(Color::WHITE).size(30).into(),
+            Column::with_children(
+                spectators
+                    .into_iter()
+                    .map(|v| {
+                        Row::with_children(v.view())
+                            .spacing(16)
+                            .align_items(Align::Center)
+                            .into()
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .into(),
+            Text::new("Recently left")
+                .color(Color::WHITE)
+                .size(30)
+                .into(),
+            Column::with_children(
+                self.recently_left
+                    .iter_mut()
+                    .rev()
+                    .map(|v| Row::with_children(v.view()).spacing(16).into())
+                    .collect::<Vec<_>>(),
+            )
+            .into(),
+            Text::new("Log").color(Color::WHITE).size(30).into(),
+            Gui::log_panel(&mut self.log_widgets, &self.logs),
+        ];
+
+        // Offer to use the server that's already running instead of this one
+        if let Some(instance) = self.running {
+            children.insert(
+                0,
+                Row::with_children(Gui::running_interactions(
+                    &mut self.running_widgets,
+                    instance,
+                ))
+                .spacing(16)
+                .align_items(Align::Center)
+                .into(),
+            );
+        }
+
+        Column::with_children(children)
+            .spacing(16)
+            .align_items(Align::Center)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .padding(16)
+            .into()
     }
 
     fn background_color(&self) -> Color {
@@ -173,12 +602,297 @@ impl Application for Gui {
 }
 
 impl Gui {
-    /// The row of buttons & inputs users can interact with the server with
-    fn server_interactions(
-        widgets: &mut Widgets,
-        server_status: ServerStatus,
+    /// What to do about another copy of the server that's already running
+    fn running_interactions(
+        widgets: &mut RunningWidgets,
+        instance: RunningInstance,
     ) -> Vec<iced::Element<'_, <Self as Application>::Message>> {
-        let mut server_interactions: Vec<iced::Element<'_, <Self as Application>::Message>> =
+        vec![
+            Text::new(format!(
+                "The server is already running at {}",
+                instance.url()
+            ))
+            .color(Color::WHITE)
+            .into(),
+            Button::new(&mut widgets.open, Text::new("Open it"))
+                .on_press(OpenRunning)
+                .padding(PADDING)
+                .style(styling::Button())
+                .into(),
+            Button::new(&mut widgets.quit, Text::new("Quit"))
+                .on_press(Quit)
+                .padding(PADDING)
+                .style(styling::Button())
+                .into(),
+        ]
+    }
+
+    /// The most recent log messages, with a button to choose which levels are shown
+    fn log_panel<'a>(
+        widgets: &'a mut LogWidgets,
+        logs: &'a VecDeque<LogEntry>,
+    ) -> iced::Element<'a, <Self as Application>::Message> {
+        let shown_level = widgets.shown_level;
+
+        // Each press shows one more level of detail, going back to just errors after trace
+        let next_level = match shown_level {
+            Level::ERROR => Level::WARN,
+            Level::WARN => Level::INFO,
+            Level::INFO => Level::DEBUG,
+            Level::DEBUG => Level::TRACE,
+            _ => Level::ERROR,
+        };
+
+        let mut scroll = Scrollable::new(&mut widgets.scroll)
+            .height(Length::Units(200))
+            .width(Length::Fill)
+            .spacing(4);
+
+        for entry in logs.iter().filter(|v| v.level <= shown_level) {
+            let color = match entry.level {
+                Level::ERROR => Color::from_rgb8(255, 96, 96),
+                Level::WARN => Color::from_rgb8(255, 200, 64),
+                Level::INFO => Color::WHITE,
+                _ => Color::from_rgb8(160, 160, 160),
+            };
+
+            scroll = scroll.push(
+                Text::new(format!("{:>5} {}", entry.level, entry.message))
+                    .color(color)
+                    .size(16),
+            );
+        }
+
+        Column::with_children(vec![
+            Button::new(
+                &mut widgets.level,
+                Text::new(format!("Showing: {}", shown_level)),
+            )
+            .on_press(InputChanged(LogLevel(next_level)))
+            .padding(PADDING)
+            .style(styling::Button())
+            .into(),
+            scroll.into(),
+        ])
+        .spacing(8)
+        .align_items(Align::Center)
+        .into()
+    }
+
+    /// A table of every character, with inputs to filter & sort it
+    fn party_dashboard<'a>(
+        widgets: &'a mut PartyWidgets,
+        characters: &'a [CharacterSummary],
+        owners: &HashMap<u32, String>,
+    ) -> iced::Element<'a, <Self as Application>::Message> {
+        let owner_name = |owner: u32| {
+            owners
+                .get(&owner)
+                .cloned()
+                .unwrap_or_else(|| format!("Player {}", owner))
+        };
+
+        let filter = widgets.filter_text.trim().to_lowercase();
+
+        let mut shown: Vec<_> = characters
+            .iter()
+            .filter(|v| {
+                [&v.name, &owner_name(v.owner), &v.room]
+                    .iter()
+                    .any(|text| text.to_lowercase().contains(&filter))
+            })
+            .collect();
+
+        let sort = widgets.sort;
+
+        shown.sort_by(|a, b| {
+            let ordering = match sort {
+                PartySort::Name => a.name.cmp(&b.name),
+                PartySort::Owner => owner_name(a.owner).cmp(&owner_name(b.owner)),
+                // The most hurt come first, since they're the ones the GM needs to keep an eye on
+                PartySort::Hp => hp_fraction(a)
+                    .partial_cmp(&hp_fraction(b))
+                    .unwrap_or(Ordering::Equal),
+                // Highest first, the same as the turn order
+                PartySort::Initiative => b.initiative.cmp(&a.initiative),
+                PartySort::Weight => b
+                    .carried_weight
+                    .partial_cmp(&a.carried_weight)
+                    .unwrap_or(Ordering::Equal),
+            };
+
+            if widgets.reversed {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+
+        let sorts = [
+            (PartySort::Name, "Name"),
+            (PartySort::Owner, "Owner"),
+            (PartySort::Hp, "HP"),
+            (PartySort::Initiative, "Initiative"),
+            (PartySort::Weight, "Weight"),
+        ];
+
+        let mut controls: Vec<iced::Element<'a, <Self as Application>::Message>> = vec![
+            TextInput::new(
+                &mut widgets.filter,
+                "Filter by name, owner or room",
+                &widgets.filter_text,
+                |text| InputChanged(PartyFilter(text)),
+            )
+            .width(Length::Units(12 * 24))
+            .padding(PADDING)
+            .style(styling::TextInput())
+            .into(),
+            Text::new("Sort by").color(Color::WHITE).into(),
+        ];
+
+        for (state, (key, label)) in widgets.sort_buttons.iter_mut().zip(sorts) {
+            let label = match (key == sort, widgets.reversed) {
+                (true, false) => format!("{} (sorted)", label),
+                (true, true) => format!("{} (reversed)", label),
+                (false, _) => label.to_owned(),
+            };
+
+            controls.push(
+                Button::new(state, Text::new(label))
+                    .on_press(InputChanged(SortParty(key)))
+                    .padding(PADDING)
+                    .style(styling::Button())
+                    .into(),
+            );
+        }
+
+        let mut rows: Vec<iced::Element<'a, <Self as Application>::Message>> =
+            vec![Row::with_children(controls)
+                .spacing(16)
+                .align_items(Align::Center)
+                .into()];
+
+        if shown.is_empty() {
+            rows.push(
+                Text::new(if characters.is_empty() {
+                    "Nobody has made a character yet"
+                } else {
+                    "No characters match the filter"
+                })
+                .color(Color::WHITE)
+                .into(),
+            );
+        }
+
+        for character in shown {
+            rows.push(
+                Row::with_children(character_row(character, owner_name(character.owner)))
+                    .spacing(16)
+                    .align_items(Align::Center)
+                    .into(),
+            );
+        }
+
+        Column::with_children(rows)
+            .spacing(8)
+            .align_items(Align::Center)
+            .into()
+    }
+
+    /// Change a setting and write it to the config file
+    fn change_config(&mut self, change: impl Fn(&mut Config)) {
+        change(&mut self.config);
+
+        // Start from what's in the file, so settings that were only overridden on the command line don't get saved
+        let mut saved = Config::load();
+        change(&mut saved);
+
+        if let Err(e) = saved.save() {
+            tracing::error!("Couldn't save the config: {}", e);
+        }
+    }
+
+    /// Add a connection, or replace what's known about it
+    fn update_connection(&mut self, info: ConnectionInfo) {
+        match self.connections.iter_mut().find(|v| v.info.id == info.id) {
+            Some(data) => data.info = info,
+            None => self.connections.push(ConnectionData::new(info)),
+        }
+    }
+
+    /// Start or stop listening on an address, it's used the next time the server starts
+    fn listen(&mut self, address: IpAddr, listen: bool) {
+        let mut addresses = self.config.bind.clone();
+
+        if listen && !addresses.contains(&address) {
+            addresses.push(address);
+        } else if !listen {
+            addresses.retain(|&v| v != address);
+        }
+
+        // The server has to listen somewhere
+        if addresses.is_empty() {
+            return;
+        }
+
+        self.server.send(SwitchAddresses {
+            addresses: addresses.clone(),
+        });
+        self.change_config(|config| config.bind = addresses.clone());
+    }
+
+    /// A button for each address the server could listen on, and an input to add another
+    fn address_interactions<'a>(
+        widgets: &'a mut AddressWidgets,
+        bind: &[IpAddr],
+    ) -> Vec<iced::Element<'a, <Self as Application>::Message>> {
+        let mut address_interactions: Vec<iced::Element<'a, <Self as Application>::Message>> =
+            vec![Text::new("Listen on").color(Color::WHITE).into()];
+
+        for option in widgets.options.iter_mut() {
+            let listening = bind.contains(&option.address);
+
+            address_interactions.push(
+                Button::new(
+                    &mut option.toggle,
+                    Text::new(format!(
+                        "{}: {}",
+                        address_label(option.address),
+                        if listening { "On" } else { "Off" }
+                    )),
+                )
+                .on_press(InputChanged(Listen(option.address, !listening)))
+                .padding(PADDING)
+                .style(styling::Button())
+                .into(),
+            );
+        }
+
+        // For an address that isn't in the list, like a hotspot's
+        address_interactions.push(
+            TextInput::new(
+                &mut widgets.new_address,
+                "Another address",
+                &widgets.new_address_text,
+                |text| InputChanged(NewAddress(text)),
+            )
+            .on_submit(AddAddress)
+            .width(Length::Units(12 * 14))
+            .padding(PADDING)
+            .style(styling::TextInput())
+            .into(),
+        );
+
+        address_interactions
+    }
+
+    /// The row of buttons & inputs users can interact with the server with
+    fn server_interactions<'a>(
+        widgets: &'a mut Widgets,
+        server_status: &ServerStatus,
+        autostart: bool,
+    ) -> Vec<iced::Element<'a, <Self as Application>::Message>> {
+        let mut server_interactions: Vec<iced::Element<'a, <Self as Application>::Message>> =
             Vec::new();
 
         // Start / Restart button
@@ -196,8 +910,26 @@ impl Gui {
             .into(),
         );
 
+        // Offer a port that's free if the chosen one is busy
+        if let Error(ServerError::PortInUse {
+            free_port: Some(free_port),
+            ..
+        }) = server_status
+        {
+            server_interactions.push(
+                Button::new(
+                    &mut widgets.use_free_port,
+                    Text::new(format!("Use port {}", free_port)),
+                )
+                .on_press(UsePort(*free_port))
+                .padding(PADDING)
+                .style(styling::Button())
+                .into(),
+            );
+        }
+
         // Stop button
-        if let Online { ip: _ } | OnlineNoIp = server_status {
+        if let Online { .. } | OnlineNoIp { .. } = server_status {
             server_interactions.push(
                 Button::new(&mut widgets.stop_server, Text::new("Stop"))
                     .on_press(ServerCommand(Stop))
@@ -211,7 +943,7 @@ impl Gui {
         server_interactions.push(
             TextInput::new(
                 &mut widgets.port,
-                "Port",
+                "Auto",
                 &widgets.port_number.to_string(),
                 |port| {
                     let filtered = port
@@ -225,27 +957,361 @@ impl Gui {
                     }
                 },
             )
+            .on_submit(SubmitPort)
             .width(Length::Units(12 * 5))
             .padding(PADDING)
             .style(styling::TextInput())
             .into(),
         );
 
+        // Whether the server starts as soon as the app opens
+        server_interactions.push(
+            Button::new(
+                &mut widgets.autostart,
+                Text::new(if autostart {
+                    "Autostart: On"
+                } else {
+                    "Autostart: Off"
+                }),
+            )
+            .on_press(InputChanged(Autostart(!autostart)))
+            .padding(PADDING)
+            .style(styling::Button())
+            .into(),
+        );
+
+        // Backups work whether or not the server is running
+        server_interactions.push(
+            Button::new(&mut widgets.export_backup, Text::new("Export"))
+                .on_press(ServerCommand(ExportBackup))
+                .padding(PADDING)
+                .style(styling::Button())
+                .into(),
+        );
+
+        server_interactions.push(
+            Button::new(&mut widgets.import_backup, Text::new("Import"))
+                .on_press(ServerCommand(ImportBackup))
+                .padding(PADDING)
+                .style(styling::Button())
+                .into(),
+        );
+
         server_interactions
     }
+
+    /// The GM PIN, and the input for the PIN players need to join
+    fn pin_interactions(
+        pin_widgets: &mut PinWidgets,
+        gm_pin: u32,
+    ) -> Vec<iced::Element<'_, <Self as Application>::Message>> {
+        vec![
+            Text::new(format!("GM PIN: {}", format_pin(gm_pin)))
+                .color(Color::WHITE)
+                .into(),
+            TextInput::new(
+                &mut pin_widgets.table_pin,
+                "Table PIN (optional)",
+                &pin_widgets.table_pin_number,
+                |pin| {
+                    if pin.len() <= PIN_DIGITS && pin.chars().all(|char| char.is_ascii_digit()) {
+                        InputChanged(TablePin(pin))
+                    } else {
+                        DoNothing
+                    }
+                },
+            )
+            .on_submit(SubmitTablePin)
+            .width(Length::Units(12 * 16))
+            .padding(PADDING)
+            .style(styling::TextInput())
+            .into(),
+        ]
+    }
 }
 
 struct ConnectionData {
-    id: u32,
-    shown: bool,
+    info: ConnectionInfo,
+
+    /// When the connection closed, None while it's still open
+    left: Option<Instant>,
+
+    kick: button::State,
+    ban: button::State,
+    mute: button::State,
 }
 
 impl ConnectionData {
-    fn view(&self) -> Vec<iced::Element<'_, <Gui as Application>::Message>> {
-        let mut data: Vec<iced::Element<'_, <Gui as Application>::Message>> = Vec::new();
+    fn new(info: ConnectionInfo) -> ConnectionData {
+        ConnectionData {
+            info,
+            left: None,
+            kick: button::State::default(),
+            ban: button::State::default(),
+            mute: button::State::default(),
+        }
+    }
+
+    /// The connection's details, with buttons to kick, ban & mute it while it's open
+    fn view(&mut self) -> Vec<iced::Element<'_, <Gui as Application>::Message>> {
+        let info = &self.info;
+
+        let name = match &info.name {
+            Some(name) => format!("{} ({})", name, info.player),
+            None => format!("Player {}", info.player),
+        };
+
+        let characters = if info.characters.is_empty() {
+            "No characters".to_owned()
+        } else {
+            info.characters.join(", ")
+        };
+
+        let columns = match self.left {
+            Some(left) => vec![
+                name,
+                characters,
+                format!("Left {} ago", format_duration(left.elapsed())),
+            ],
+            None => vec![
+                name,
+                match info.role {
+                    Role::Player => "Player",
+                    Role::Gm => "GM",
+                    Role::Spectator => "Spectator",
+                }
+                .to_owned(),
+                info.address.ip().to_string(),
+                format!(
+                    "Connected for {}",
+                    format_duration(
+                        SystemTime::now()
+                            .duration_since(info.connected_since)
+                            .unwrap_or_default()
+                    )
+                ),
+                match info.latency {
+                    Some(latency) => format!("{} ms", latency.as_millis()),
+                    None => "? ms".to_owned(),
+                },
+                characters,
+            ],
+        };
+
+        let mut row: Vec<iced::Element<'_, <Gui as Application>::Message>> = columns
+            .into_iter()
+            .map(|v| Text::new(v).color(Color::WHITE).into())
+            .collect();
+
+        if self.left.is_none() {
+            let id = info.id;
+
+            row.push(
+                Button::new(&mut self.kick, Text::new("Kick"))
+                    .on_press(ServerCommand(Kick { id }))
+                    .padding(PADDING)
+                    .style(styling::Button())
+                    .into(),
+            );
+
+            row.push(
+                // Bans go by address too, so everyone else on the same network is kept out as well
+                Button::new(&mut self.ban, Text::new("Ban (whole IP)"))
+                    .on_press(ServerCommand(Ban { id }))
+                    .padding(PADDING)
+                    .style(styling::Button())
+                    .into(),
+            );
+
+            row.push(
+                Button::new(
+                    &mut self.mute,
+                    Text::new(if info.muted { "Unmute" } else { "Mute" }),
+                )
+                .on_press(ServerCommand(if info.muted {
+                    Unmute { id }
+                } else {
+                    Mute { id }
+                }))
+                .padding(PADDING)
+                .style(styling::Button())
+                .into(),
+            );
+        }
+
+        row
+    }
+}
+
+/// One character's row in the party dashboard
+fn character_row<'a>(
+    character: &CharacterSummary,
+    owner: String,
+) -> Vec<iced::Element<'a, <Gui as Application>::Message>> {
+    let hp = match (character.hp_max, character.temp_hp) {
+        (Some(max), 0) => format!("{}/{}", character.hp, max),
+        (Some(max), temp) => format!("{}/{} (+{})", character.hp, max, temp),
+        (None, 0) => character.hp.to_string(),
+        (None, temp) => format!("{} (+{})", character.hp, temp),
+    };
+
+    let initiative = match character.initiative {
+        Some(initiative) if character.taking_turn => format!("{} (their turn)", initiative),
+        Some(initiative) => initiative.to_string(),
+        None => "No initiative".to_owned(),
+    };
+
+    // Levels without any slots aren't worth a column
+    let spell_slots: Vec<_> = character
+        .spell_slots
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, max))| *max > 0)
+        .map(|(level, (remaining, max))| format!("{} {}/{}", ordinal(level + 1), remaining, max))
+        .collect();
+
+    let spell_slots = if spell_slots.is_empty() {
+        "No spell slots".to_owned()
+    } else {
+        spell_slots.join(", ")
+    };
+
+    let fraction = hp_fraction(character);
 
-        data.push(Text::new(self.id.to_string()).color(Color::WHITE).into());
+    vec![
+        Text::new(format!("{} ({})", character.name, character.room))
+            .color(Color::WHITE)
+            .width(Length::Units(200))
+            .into(),
+        Text::new(owner)
+            .color(Color::WHITE)
+            .width(Length::Units(120))
+            .into(),
+        Column::with_children(vec![
+            ProgressBar::new(0.0..=1.0, fraction)
+                .width(Length::Units(120))
+                .height(Length::Units(8))
+                .style(styling::HpBar(fraction))
+                .into(),
+            Text::new(hp).color(Color::WHITE).size(16).into(),
+        ])
+        .spacing(4)
+        .width(Length::Units(120))
+        .into(),
+        Text::new(initiative)
+            .color(Color::WHITE)
+            .width(Length::Units(140))
+            .into(),
+        Text::new(spell_slots)
+            .color(Color::WHITE)
+            .width(Length::Units(240))
+            .into(),
+        Text::new(format!("{:.1} lb", character.carried_weight))
+            .color(Color::WHITE)
+            .width(Length::Units(80))
+            .into(),
+    ]
+}
+
+/// How much of their HP a character has left, characters without a maximum count as unhurt unless they're down
+fn hp_fraction(character: &CharacterSummary) -> f32 {
+    match character.hp_max {
+        Some(max) if max > 0 => (character.hp as f32 / max as f32).clamp(0.0, 1.0),
+        _ if character.hp <= 0 => 0.0,
+        _ => 1.0,
+    }
+}
+
+/// `1st`, `2nd`, `3rd` and so on, for spell levels
+fn ordinal(number: usize) -> String {
+    let suffix = match number {
+        1 => "st",
+        2 => "nd",
+        3 => "rd",
+        _ => "th",
+    };
+
+    format!("{}{}", number, suffix)
+}
+
+/// Write a duration the way people would say it, like `3m 20s`
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds / 60 % 60),
+    }
+}
+
+/// Open a page in the default browser
+fn open_in_browser(url: &str) {
+    let result = if cfg!(target_os = "windows") {
+        process::Command::new("cmd")
+            .args(["/C", "start", url])
+            .spawn()
+    } else if cfg!(target_os = "macos") {
+        process::Command::new("open").arg(url).spawn()
+    } else {
+        process::Command::new("xdg-open").arg(url).spawn()
+    };
+
+    if let Err(e) = result {
+        tracing::warn!("Couldn't open {} in the browser: {}", url, e);
+    }
+}
+
+impl AddressWidgets {
+    /// Offer the usual addresses, this computer's address, and any others the server's set to listen on
+    fn new(bind: &[IpAddr]) -> AddressWidgets {
+        let mut widgets = AddressWidgets {
+            options: Vec::new(),
+            new_address: text_input::State::default(),
+            new_address_text: String::new(),
+        };
+
+        let usual = [
+            IpAddr::from([0u16; 8]),
+            IpAddr::from([0, 0, 0, 0]),
+            IpAddr::from([127, 0, 0, 1]),
+        ];
+
+        for &address in usual
+            .iter()
+            .chain(server::get_local_ip().iter())
+            .chain(bind)
+        {
+            widgets.add(address);
+        }
+
+        widgets
+    }
+
+    fn add(&mut self, address: IpAddr) {
+        if !self.options.iter().any(|v| v.address == address) {
+            self.options.push(AddressOption {
+                address,
+                toggle: button::State::default(),
+            });
+        }
+    }
+}
+
+/// What listening on an address means, for the buttons to choose them
+fn address_label(address: IpAddr) -> String {
+    match address {
+        IpAddr::V6(v6) if v6.is_unspecified() => "Every address (::)".to_owned(),
+        IpAddr::V4(v4) if v4.is_unspecified() => "IPv4 only (0.0.0.0)".to_owned(),
+        v if v.is_loopback() => format!("This computer only ({})", v),
+        v => v.to_string(),
+    }
+}
 
-        data
+/// What the port input shows for a port, it's left empty to let the server pick
+fn port_text(port: Port) -> String {
+    match port {
+        Port::Auto => String::new(),
+        Port::Fixed(port) => port.to_string(),
     }
 }
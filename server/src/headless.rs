@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use futures::StreamExt;
+use server::{format_pin, Config, Server, ServerCommand, ServerMessage, ServerStatus};
+
+/// How to run the server without the GUI
+pub struct HeadlessOptions {
+    pub config: Config,
+
+    /// A random one is made if it's None
+    pub gm_pin: Option<u32>,
+}
+
+/// Run the server without opening a window, printing what it's doing until it's stopped with Ctrl+C
+pub fn run(options: HeadlessOptions) {
+    let runtime = Arc::new(
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap(),
+    );
+
+    // There's no button to start it, so it always starts straight away
+    let server = Server::new(
+        Arc::clone(&runtime),
+        Config {
+            autostart: true,
+            ..options.config
+        },
+        options.gm_pin,
+    );
+
+    let mut events = server.events();
+
+    println!("GM PIN: {}", format_pin(server.gm_pin()));
+
+    runtime.block_on(async {
+        let mut stopping = false;
+
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(ServerMessage::Status(status)) => print_status(status),
+                    Some(message) => print_message(message),
+
+                    // The events only end once the server's finished saving and shut down
+                    None => break,
+                },
+
+                result = tokio::signal::ctrl_c(), if !stopping => {
+                    if let Err(e) = result {
+                        eprintln!("Couldn't listen for Ctrl+C: {}", e);
+                    }
+
+                    println!("Stopping");
+                    stopping = true;
+
+                    server.send(ServerCommand::Stop);
+                    server.send(ServerCommand::Join);
+                }
+            }
+        }
+    });
+}
+
+fn print_status(status: ServerStatus) {
+    match status {
+        ServerStatus::Online { port, .. } | ServerStatus::OnlineNoIp { port, .. } => {
+            let urls = status.urls();
+
+            if urls.is_empty() {
+                println!("Online on port {}", port);
+            }
+
+            for url in urls {
+                println!("Online at {}", url);
+            }
+        }
+        ServerStatus::Restarting => println!("Restarting"),
+        ServerStatus::Offline => println!("Offline"),
+        ServerStatus::Error(error) => match error.suggestion() {
+            Some(suggestion) => println!("{}. {}", error, suggestion),
+            None => println!("{}", error),
+        },
+    }
+}
+
+fn print_message(message: ServerMessage) {
+    match message {
+        ServerMessage::NewConnection { connection } => println!(
+            "Connection {} joined from {} as {:?}{}",
+            connection.id,
+            connection.address.ip(),
+            connection.role,
+            match connection.name {
+                Some(name) => format!(", called {}", name),
+                None => String::new(),
+            }
+        ),
+        ServerMessage::ConnectionChanged { .. } | ServerMessage::CharactersChanged { .. } => {}
+        ServerMessage::ClosedConnection { id } => println!("Connection {} left", id),
+        ServerMessage::RoomsChanged { rooms } => {
+            for (name, players) in rooms {
+                println!("Room {}: {} connected", name, players);
+            }
+        }
+        ServerMessage::BackupFinished { message } => println!("{}", message),
+        ServerMessage::Status(_) => {}
+    }
+}
@@ -0,0 +1,9 @@
+//! The server for D&D stuff, which can be run from the GUI, without one, or embedded in something else
+//!
+//! Make a [`Server`], send it [`ServerCommand`]s, and follow what it's doing with [`Server::events`]
+
+mod server;
+mod utils;
+
+pub use server::*;
+pub use utils::{get_data_dir, get_local_ip, set_data_dir};
@@ -0,0 +1,166 @@
+use std::{
+    fmt::{Debug, Write},
+    fs,
+};
+
+use server::LoggingConfig;
+use tokio::sync::broadcast;
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter::LevelFilter,
+    fmt::{
+        self,
+        format::{DefaultFields, Writer},
+        FormatFields, FormattedFields,
+    },
+    layer::Context,
+    prelude::*,
+    registry::LookupSpan,
+    Layer,
+};
+
+/// How many days of log files are kept, older ones are deleted
+const MAX_LOG_FILES: usize = 7;
+
+/// A message that was logged, as it's shown in the GUI
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub level: Level,
+
+    /// The message, along with the spans it was logged in, like which connection it's about
+    pub message: String,
+}
+
+/// Start logging to the terminal, to files in the data directory if the config says to, and to the GUI
+///
+/// Subscribe to the returned sender to receive everything that's logged
+pub fn init(config: &LoggingConfig) -> broadcast::Sender<LogEntry> {
+    let level = config.level.parse::<LevelFilter>().unwrap_or_else(|_| {
+        eprintln!(
+            "`{}` isn't a log level, it should be error, warn, info, debug or trace",
+            config.level
+        );
+        LevelFilter::INFO
+    });
+
+    let (sender, _) = broadcast::channel(256);
+
+    let file = if config.to_file {
+        let dir = server::get_data_dir().join("logs");
+
+        // A new file is started every day, so the log doesn't grow forever
+        let appender = fs::create_dir_all(&dir)
+            .map_err(|e| e.to_string())
+            .and_then(|_| {
+                RollingFileAppender::builder()
+                    .rotation(Rotation::DAILY)
+                    .filename_prefix("server")
+                    .filename_suffix("log")
+                    .max_log_files(MAX_LOG_FILES)
+                    .build(&dir)
+                    .map_err(|e| e.to_string())
+            });
+
+        match appender {
+            Ok(v) => Some(
+                fmt::layer()
+                    .fmt_fields(FileFields::default())
+                    .with_ansi(false)
+                    .with_writer(v),
+            ),
+            Err(e) => {
+                eprintln!(
+                    "Couldn't open the log file, only logging to the terminal: {}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        // The GUI shows the span fields the terminal formatted, so the terminal can't have colors or they'd end up in the GUI
+        .with(fmt::layer().with_ansi(false).with_writer(std::io::stderr))
+        .with(file)
+        .with(GuiLayer {
+            sender: sender.clone(),
+        })
+        .with(level)
+        .init();
+
+    sender
+}
+
+/// Sends everything that's logged to the GUI
+struct GuiLayer {
+    sender: broadcast::Sender<LogEntry>,
+}
+
+impl<S> Layer<S> for GuiLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = String::new();
+
+        // The spans say what the message is about, like `connection{id=3 player=12}`
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                message.push_str(span.name());
+
+                if let Some(fields) = span.extensions().get::<FormattedFields<DefaultFields>>() {
+                    if !fields.is_empty() {
+                        write!(message, "{{{}}}", fields).ok();
+                    }
+                }
+
+                message.push_str(": ");
+            }
+        }
+
+        event.record(&mut MessageVisitor(&mut message));
+
+        self.sender
+            .send(LogEntry {
+                level: *event.metadata().level(),
+                message,
+            })
+            .ok();
+    }
+}
+
+/// Formats fields the same way as `DefaultFields`, but as a different type
+///
+/// Each layer keeps its formatted span fields under the formatter's type, so if the file used `DefaultFields` too, fields recorded later would be added twice
+#[derive(Default)]
+struct FileFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for FileFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        writer: Writer<'writer>,
+        fields: R,
+    ) -> std::fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+/// Writes an event's message, followed by any other fields it has
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            write!(self.0, "{:?}", value).ok();
+        } else {
+            write!(self.0, " {}={:?}", field.name(), value).ok();
+        }
+    }
+}
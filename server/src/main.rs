@@ -1,11 +1,180 @@
-use gui::Gui;
+use std::{fmt::Display, net::IpAddr, path::PathBuf, str::FromStr};
+
+use gui::{Gui, GuiFlags};
+use headless::HeadlessOptions;
 use iced::{Application, Settings};
+use server::{Config, Port, StorageKind};
 
 mod gui;
-mod server;
+mod headless;
+mod logging;
 mod styling;
-mod utils;
+mod subscription;
+
+/// The options the app can be started with, read from the command line
+struct Options {
+    /// Run without the GUI, printing what's happening instead
+    headless: bool,
+
+    // These override the settings in the config
+    port: Option<Port>,
+    addresses: Option<Vec<IpAddr>>,
+    storage: Option<StorageKind>,
+
+    data_dir: Option<PathBuf>,
+    gm_pin: Option<u32>,
+
+    /// `replay <unix time>` prints every room as it was at that time, in the same format as a backup
+    replay: Option<String>,
+}
 
 fn main() -> iced::Result {
-    Gui::run(Settings::default())
+    let options = match parse_options(std::env::args().skip(1).collect()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: server [--headless] [--port <port|auto>] [--bind <address,...>] [--data-dir <path>] [--gm-pin <pin>] [--storage <file|sqlite|memory>] [replay <unix time>]");
+            return Ok(());
+        }
+    };
+
+    // The config's in the data directory, so it has to be moved first
+    if let Some(dir) = options.data_dir {
+        server::set_data_dir(dir);
+    }
+
+    let mut config = Config::load();
+
+    if let Some(port) = options.port {
+        config.port = port;
+    }
+    if let Some(addresses) = options.addresses {
+        config.bind = addresses;
+    }
+    if let Some(storage) = options.storage {
+        config.storage = storage;
+    }
+
+    let logs = logging::init(&config.logging);
+
+    if let Some(timestamp) = options.replay {
+        replay(config.storage, &timestamp);
+        return Ok(());
+    }
+
+    // Starting a second copy would only fail to listen on the same port, so point the user at the one that's running
+    let running = server::running_instance();
+
+    if options.headless {
+        if let Some(instance) = running {
+            eprintln!("Another server is already running at {}", instance.url());
+            return Ok(());
+        }
+
+        headless::run(HeadlessOptions {
+            config,
+            gm_pin: options.gm_pin,
+        });
+        return Ok(());
+    }
+
+    Gui::run(Settings::with_flags(GuiFlags {
+        config,
+        logs,
+        running,
+    }))
+}
+
+fn parse_options(mut args: Vec<String>) -> Result<Options, String> {
+    let headless = take_switch(&mut args, "--headless");
+
+    let gm_pin = take_flag::<u32>(&mut args, "--gm-pin")?;
+    if gm_pin.is_some_and(|v| v.to_string().len() > server::PIN_DIGITS) {
+        return Err(format!(
+            "The GM PIN should be at most {} digits",
+            server::PIN_DIGITS
+        ));
+    }
+
+    let options = Options {
+        headless,
+        port: take_flag(&mut args, "--port")?,
+        addresses: take_flag::<String>(&mut args, "--bind")?
+            .map(|v| parse_addresses(&v))
+            .transpose()?,
+        storage: take_flag(&mut args, "--storage")?,
+        data_dir: take_flag(&mut args, "--data-dir")?,
+        gm_pin,
+        replay: None,
+    };
+
+    match args.as_slice() {
+        [] => Ok(options),
+        [command, timestamp] if command == "replay" => Ok(Options {
+            replay: Some(timestamp.clone()),
+            ..options
+        }),
+        [unknown, ..] => Err(format!("Unknown argument `{}`", unknown)),
+    }
+}
+
+/// Parse a comma separated list of addresses to listen on
+fn parse_addresses(list: &str) -> Result<Vec<IpAddr>, String> {
+    list.split(',')
+        .map(|v| {
+            v.trim()
+                .parse()
+                .map_err(|_| format!("`{}` isn't an IP address", v))
+        })
+        .collect()
+}
+
+/// Remove a flag without a value from the arguments, returning whether it was there
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    match args.iter().position(|v| v == flag) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Remove a flag and the value after it from the arguments, parsing the value
+fn take_flag<T>(args: &mut Vec<String>, flag: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let i = match args.iter().position(|v| v == flag) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    if i + 1 >= args.len() {
+        return Err(format!("{} needs a value after it", flag));
+    }
+
+    let value = args.remove(i + 1);
+    args.remove(i);
+
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| format!("Invalid value for {}: {}", flag, e))
+}
+
+fn replay(storage: StorageKind, timestamp: &str) {
+    let timestamp = match timestamp.parse() {
+        Ok(v) => v,
+        Err(_) => {
+            eprintln!("The time to replay to should be in seconds since the unix epoch");
+            return;
+        }
+    };
+
+    match server::replay(storage, timestamp) {
+        Ok(rooms) => println!("{}", String::from_utf8_lossy(&rooms)),
+        Err(e) => eprintln!("Couldn't replay the event log: {}", e),
+    }
 }
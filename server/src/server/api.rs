@@ -0,0 +1,150 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use hyper::{body::HttpBody, header, Body, Method, Request, Response, StatusCode};
+use tokio::sync::{watch, RwLock};
+
+use super::{
+    instance::StatusResponse,
+    moderation::{Moderation, PinKind},
+    persistence,
+    rooms::Rooms,
+    session::Pins,
+};
+
+/// The biggest backup that can be restored, the whole body is read into memory before it's checked
+const MAX_BACKUP_SIZE: usize = 32 * 1024 * 1024;
+
+/// Handle requests to the HTTP API
+///
+/// Everything in the API other than the status can see or replace the whole game, so it needs the GM PIN in the `pin` query parameter
+///
+/// Wrong PINs count towards the same limit as the ones entered in the website
+pub(super) async fn handle_api(
+    request: Request<Body>,
+    remote_address: SocketAddr,
+    rooms: Arc<RwLock<Rooms>>,
+    pins: watch::Receiver<Pins>,
+    moderation: &Mutex<Moderation>,
+) -> Response<Body> {
+    // The status doesn't say anything about the game, it's so other copies of the server can tell this one's running
+    if (request.method(), request.uri().path()) == (&Method::GET, "/api/status") {
+        return Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::to_vec(&StatusResponse::current()).unwrap(),
+            ))
+            .unwrap();
+    }
+
+    let address = remote_address.ip();
+
+    {
+        let mut moderation = moderation.lock().unwrap();
+
+        if let Some(lockout) = moderation.pin_lockout(address, PinKind::Gm) {
+            return respond(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Too many wrong GM PINs, try again in {} seconds",
+                    lockout.as_secs() + 1
+                ),
+            );
+        }
+
+        let correct = pin_of(&request) == Some(pins.borrow().gm);
+
+        moderation.pin_attempted(address, PinKind::Gm, correct);
+
+        if !correct {
+            return respond(StatusCode::UNAUTHORIZED, "The GM PIN is missing or wrong");
+        }
+    }
+
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/api/backup") => match persistence::backup(&rooms).await {
+            Ok(backup) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"dnd-stuff-backup.json\"",
+                )
+                .body(Body::from(backup))
+                .unwrap(),
+
+            Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        },
+
+        (&Method::POST, "/api/restore") => {
+            let too_large = || {
+                respond(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!(
+                        "Backups can't be bigger than {} MB",
+                        MAX_BACKUP_SIZE / 1024 / 1024
+                    ),
+                )
+            };
+
+            // Don't start reading something that says it's too big
+            let length = request
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+
+            if length.is_some_and(|v| v > MAX_BACKUP_SIZE) {
+                return too_large();
+            }
+
+            let raw = match read_limited(request.into_body(), MAX_BACKUP_SIZE).await {
+                Ok(Some(v)) => v,
+                Ok(None) => return too_large(),
+                Err(e) => return respond(StatusCode::BAD_REQUEST, e.to_string()),
+            };
+
+            match persistence::restore(&rooms, &raw).await {
+                Ok(restored) => respond(StatusCode::OK, format!("Restored {} rooms", restored)),
+                Err(e) => respond(StatusCode::BAD_REQUEST, e),
+            }
+        }
+
+        _ => respond(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Read the whole body, None if it turns out to be bigger than `limit`
+async fn read_limited(mut body: Body, limit: usize) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut raw = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+
+        if raw.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+
+        raw.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(raw))
+}
+
+/// The PIN in the request's query string
+fn pin_of(request: &Request<Body>) -> Option<u32> {
+    request
+        .uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("pin="))?
+        .parse()
+        .ok()
+}
+
+pub(super) fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
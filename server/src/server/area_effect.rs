@@ -0,0 +1,91 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{
+    damage::{apply_damage, DamageLogEntry},
+    dice::{Dice, RollLogEntry},
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) enum Ability {
+    Strength,
+    Dexterity,
+    Constitution,
+    Intelligence,
+    Wisdom,
+    Charisma,
+}
+
+/// How one target of an area effect fared
+#[derive(Debug, Clone, Serialize)]
+pub(super) struct AreaEffectTarget {
+    pub character: String,
+
+    /// The d20 roll, before adding the modifier
+    pub roll: i64,
+    pub modifier: i64,
+    pub saved: bool,
+    pub damage: DamageLogEntry,
+}
+
+impl Ability {
+    /// The key of the ability in a character's `saving_throws`
+    fn key(&self) -> &'static str {
+        match self {
+            Ability::Strength => "strength",
+            Ability::Dexterity => "dexterity",
+            Ability::Constitution => "constitution",
+            Ability::Intelligence => "intelligence",
+            Ability::Wisdom => "wisdom",
+            Ability::Charisma => "charisma",
+        }
+    }
+}
+
+/// Roll a saving throw for a target and deal full damage if they fail, or half if they succeed
+///
+/// Returns the outcome, along with the roll so it can be logged
+pub(super) fn resolve_target(
+    character: &str,
+    data: &mut Map<String, Value>,
+    damage: u32,
+    damage_type: Option<&str>,
+    save: Ability,
+    dc: i64,
+) -> (AreaEffectTarget, RollLogEntry) {
+    let modifier = saving_throw_modifier(data, save);
+
+    let roll = Dice::d20().roll();
+
+    let saved = roll + modifier >= dc;
+
+    let damage = apply_damage(
+        character,
+        data,
+        if saved { damage / 2 } else { damage },
+        damage_type,
+    );
+
+    (
+        AreaEffectTarget {
+            character: character.to_owned(),
+            roll,
+            modifier,
+            saved,
+            damage,
+        },
+        RollLogEntry::new(
+            format!("{} {:?} save", character, save),
+            &format!("d20 + {}", modifier),
+            roll + modifier,
+        ),
+    )
+}
+
+/// The modifier a character adds to saving throws for an ability, 0 if the client hasn't set it
+fn saving_throw_modifier(data: &Map<String, Value>, ability: Ability) -> i64 {
+    data.get("saving_throws")
+        .and_then(|v| v.get(ability.key()))
+        .and_then(Value::as_i64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+
+/// A condition affecting a character, like poisoned, prone, concentrating or blessed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Condition {
+    pub name: String,
+
+    /// Who or what caused the condition, like the name of the spell or the creature that cast it
+    pub source: String,
+
+    pub duration: Duration,
+
+    /// Which creature's turn the condition ticks down on
+    pub expires: Expiry,
+
+    /// How many more times the condition has to tick before it expires
+    ///
+    /// This is calculated from the duration when the condition is added, so clients don't need to send it
+    #[serde(default)]
+    pub remaining: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) enum Duration {
+    /// Ticks once per round, on the turn of the creature in `expires`
+    Rounds(u32),
+
+    /// Ticks on every creature's turn in the initiative order
+    Turns(u32),
+
+    /// A minute is 10 rounds
+    Minutes(u32),
+}
+
+/// When a condition ticks, the name is the creature whose turn it's tied to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum Expiry {
+    StartOfTurn(String),
+    EndOfTurn(String),
+}
+
+/// A point in the initiative order where conditions can tick
+#[derive(Debug, Clone, Copy)]
+pub(super) enum TurnBoundary<'a> {
+    Start(&'a str),
+    End(&'a str),
+}
+
+impl Condition {
+    /// Reset the amount of ticks left to match the condition's duration
+    pub fn reset(&mut self) {
+        self.remaining = match self.duration {
+            Duration::Rounds(rounds) => rounds,
+            Duration::Turns(turns) => turns,
+            // Anything that long outlasts the session anyway
+            Duration::Minutes(minutes) => minutes.saturating_mul(10),
+        };
+    }
+
+    /// Tick the condition if the boundary is one it cares about
+    ///
+    /// Returns true if the condition expired
+    pub fn tick(&mut self, boundary: TurnBoundary) -> bool {
+        let ticks = match (&self.expires, boundary) {
+            (Expiry::StartOfTurn(creature), TurnBoundary::Start(turn))
+            | (Expiry::EndOfTurn(creature), TurnBoundary::End(turn)) => {
+                matches!(self.duration, Duration::Turns(_)) || creature == turn
+            }
+
+            _ => false,
+        };
+
+        if ticks {
+            self.remaining = self.remaining.saturating_sub(1);
+        }
+
+        ticks && self.remaining == 0
+    }
+}
+
+/// Tick every condition in the list, removing and returning the ones that expired
+pub(super) fn tick_conditions(
+    conditions: &mut Vec<Condition>,
+    boundary: TurnBoundary,
+) -> Vec<Condition> {
+    let mut expired = Vec::new();
+
+    let mut i = 0;
+
+    while i < conditions.len() {
+        if conditions[i].tick(boundary) {
+            expired.push(conditions.remove(i));
+        } else {
+            i += 1;
+        }
+    }
+
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_helpers::condition;
+
+    #[test]
+    fn rounds_only_tick_on_their_creatures_turn() {
+        let mut conditions = vec![condition(
+            "Blessed",
+            Duration::Rounds(2),
+            Expiry::EndOfTurn("Cleric".to_owned()),
+        )];
+
+        assert!(tick_conditions(&mut conditions, TurnBoundary::End("Fighter")).is_empty());
+        assert!(tick_conditions(&mut conditions, TurnBoundary::Start("Cleric")).is_empty());
+        assert!(tick_conditions(&mut conditions, TurnBoundary::End("Cleric")).is_empty());
+
+        let expired = tick_conditions(&mut conditions, TurnBoundary::End("Cleric"));
+
+        assert_eq!(expired.len(), 1);
+        assert!(conditions.is_empty());
+    }
+
+    #[test]
+    fn turns_tick_on_every_turn() {
+        let mut conditions = vec![condition(
+            "Dodging",
+            Duration::Turns(2),
+            Expiry::StartOfTurn("Rogue".to_owned()),
+        )];
+
+        assert!(tick_conditions(&mut conditions, TurnBoundary::Start("Goblin")).is_empty());
+        assert_eq!(
+            tick_conditions(&mut conditions, TurnBoundary::Start("Wizard")).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn minutes_are_ten_rounds() {
+        let haste = condition(
+            "Haste",
+            Duration::Minutes(1),
+            Expiry::StartOfTurn("Wizard".to_owned()),
+        );
+
+        assert_eq!(haste.remaining, 10);
+
+        let forever = condition(
+            "Curse",
+            Duration::Minutes(u32::MAX),
+            Expiry::StartOfTurn("Wizard".to_owned()),
+        );
+
+        assert_eq!(forever.remaining, u32::MAX);
+    }
+}
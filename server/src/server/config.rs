@@ -0,0 +1,238 @@
+use std::{
+    fmt::{self, Display},
+    fs,
+    io::ErrorKind,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::utils::{get_data_dir, write_atomically};
+
+use super::StorageKind;
+
+/// The server's settings, kept in `config.toml` in the data directory
+///
+/// Anything missing from the file gets its default, so the file only needs the settings that were changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub port: Port,
+
+    /// The addresses to listen for connections on, written as one address or a list
+    ///
+    /// `::` listens on every IPv4 and IPv6 address, 0.0.0.0 on every IPv4 address, and an interface's own address only on that interface
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind: Vec<IpAddr>,
+
+    /// Start the server as soon as the app opens
+    pub autostart: bool,
+
+    pub storage: StorageKind,
+    pub grace_periods: GracePeriods,
+    pub rate_limits: RateLimits,
+    pub rooms: RoomsConfig,
+    pub logging: LoggingConfig,
+}
+
+/// The port the server listens on when the port is picked automatically and it's free
+pub(super) const DEFAULT_PORT: u16 = 8000;
+
+/// Which port the server listens on, written as a number or `"auto"` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    /// Use the default port if it's free, otherwise any free port
+    Auto,
+    Fixed(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GracePeriods {
+    /// How many seconds a player's characters stay in a room after they leave, so they can reconnect without losing them
+    pub reconnect: u64,
+}
+
+/// How many messages each connection can send, anything over the limit is turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub messages_per_second: u32,
+
+    /// How many messages can be sent at once before the limit kicks in
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomsConfig {
+    /// Campaigns that have a room ready as soon as the server starts, even if nobody's played them yet
+    pub open_on_start: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// The least important kind of message that gets logged, one of error, warn, info, debug or trace
+    pub level: String,
+
+    /// Write the log to files in the data directory as well as to the terminal
+    pub to_file: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            port: Port::Fixed(DEFAULT_PORT),
+            bind: vec![IpAddr::from([0u16; 8])],
+            autostart: false,
+            storage: StorageKind::default(),
+            grace_periods: GracePeriods::default(),
+            rate_limits: RateLimits::default(),
+            rooms: RoomsConfig::default(),
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for GracePeriods {
+    fn default() -> GracePeriods {
+        GracePeriods { reconnect: 120 }
+    }
+}
+
+impl GracePeriods {
+    pub fn reconnect(&self) -> Duration {
+        Duration::from_secs(self.reconnect)
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> RateLimits {
+        RateLimits {
+            messages_per_second: 20,
+            burst: 60,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig {
+            level: "info".to_owned(),
+            to_file: true,
+        }
+    }
+}
+
+impl Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Port::Auto => write!(f, "auto"),
+            Port::Fixed(port) => write!(f, "{}", port),
+        }
+    }
+}
+
+impl FromStr for Port {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Port, String> {
+        if s == "auto" {
+            return Ok(Port::Auto);
+        }
+
+        s.parse()
+            .map(Port::Fixed)
+            .map_err(|_| format!("`{}` isn't a port, it should be a number or auto", s))
+    }
+}
+
+impl Serialize for Port {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Port::Auto => serializer.serialize_str("auto"),
+            Port::Fixed(port) => serializer.serialize_u16(*port),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Port {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Port, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum RawPort {
+            Number(u16),
+            Text(String),
+        }
+
+        match RawPort::deserialize(deserializer)? {
+            RawPort::Number(port) => Ok(Port::Fixed(port)),
+            RawPort::Text(text) => text.parse().map_err(D::Error::custom),
+        }
+    }
+}
+
+/// Read either a single value or a list of them
+fn one_or_many<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn config_path() -> PathBuf {
+    get_data_dir().join("config.toml")
+}
+
+impl Config {
+    /// Load the config from the data directory, or the defaults if there isn't one
+    ///
+    /// If the config can't be read, it's moved out of the way so saving the settings doesn't overwrite it
+    ///
+    /// Problems are printed rather than logged, since logging is set up from the config
+    pub fn load() -> Config {
+        let path = config_path();
+
+        let raw = match fs::read_to_string(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Config::default(),
+            Err(e) => {
+                eprintln!("Couldn't read the config: {}", e);
+                return Config::default();
+            }
+        };
+
+        match toml::from_str(&raw) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("The config is invalid, using the defaults: {}", e);
+
+                if let Err(e) = fs::rename(&path, path.with_extension("broken.toml")) {
+                    eprintln!("Couldn't move the invalid config out of the way: {}", e);
+                }
+
+                Config::default()
+            }
+        }
+    }
+
+    /// Write the config to the data directory
+    pub fn save(&self) -> Result<(), String> {
+        let serialized = toml::to_string_pretty(self).map_err(|e| e.to_string())?;
+
+        write_atomically(&config_path(), serialized.as_bytes()).map_err(|e| e.to_string())
+    }
+}
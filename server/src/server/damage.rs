@@ -0,0 +1,231 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::utils::unix_time;
+
+/// A record of damage or healing the server applied to a character
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct DamageLogEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub character: String,
+    pub kind: DamageKind,
+
+    /// The amount of damage or healing that was asked for
+    pub amount: u32,
+
+    /// The amount actually dealt after resistances, vulnerabilities, immunities and temporary HP
+    pub applied: u32,
+
+    pub hp: i64,
+    pub temp_hp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum DamageKind {
+    Damage { damage_type: Option<String> },
+    Healing,
+}
+
+/// Deal damage to a character's data, taking it from temporary HP first
+pub(super) fn apply_damage(
+    character: &str,
+    data: &mut Map<String, Value>,
+    amount: u32,
+    damage_type: Option<&str>,
+) -> DamageLogEntry {
+    let mut dealt = amount;
+
+    if let Some(damage_type) = damage_type {
+        if has_damage_type(data, "immunities", damage_type) {
+            dealt = 0;
+        }
+
+        if has_damage_type(data, "resistances", damage_type) {
+            dealt /= 2;
+        }
+
+        if has_damage_type(data, "vulnerabilities", damage_type) {
+            dealt = dealt.saturating_mul(2);
+        }
+    }
+
+    let (hp, hp_max) = hp_of(data);
+    let temp_hp = get_i64(data, "temp_hp").unwrap_or(0).max(0);
+
+    let absorbed = temp_hp.min(dealt as i64);
+    let temp_hp = temp_hp - absorbed;
+    let hp = hp
+        .saturating_sub(dealt as i64 - absorbed)
+        .clamp(0, hp_max.unwrap_or(i64::MAX).max(0));
+
+    data.insert("hp".to_owned(), hp.into());
+    data.insert("temp_hp".to_owned(), temp_hp.into());
+
+    DamageLogEntry {
+        timestamp: unix_time(),
+        character: character.to_owned(),
+        kind: DamageKind::Damage {
+            damage_type: damage_type.map(str::to_owned),
+        },
+        amount,
+        applied: dealt,
+        hp,
+        temp_hp,
+    }
+}
+
+/// Heal a character's data, healing can't bring a character above their maximum HP
+pub(super) fn apply_healing(
+    character: &str,
+    data: &mut Map<String, Value>,
+    amount: u32,
+) -> DamageLogEntry {
+    let (old_hp, hp_max) = hp_of(data);
+    let temp_hp = get_i64(data, "temp_hp").unwrap_or(0).max(0);
+
+    let hp = old_hp
+        .saturating_add(amount.into())
+        .clamp(0, hp_max.unwrap_or(i64::MAX).max(old_hp).max(0));
+
+    data.insert("hp".to_owned(), hp.into());
+
+    DamageLogEntry {
+        timestamp: unix_time(),
+        character: character.to_owned(),
+        kind: DamageKind::Healing,
+        amount,
+        applied: hp.saturating_sub(old_hp).clamp(0, amount.into()) as u32,
+        hp,
+        temp_hp,
+    }
+}
+
+/// The character's current & maximum HP, a character that hasn't set their current HP is assumed to be at full health
+pub(super) fn hp_of(data: &Map<String, Value>) -> (i64, Option<i64>) {
+    let hp_max = get_i64(data, "hp_max");
+    let hp = get_i64(data, "hp").or(hp_max).unwrap_or(0);
+
+    (hp, hp_max)
+}
+
+pub(super) fn get_i64(data: &Map<String, Value>, key: &str) -> Option<i64> {
+    data.get(key)?.as_i64()
+}
+
+/// Whether the list of damage types under `key` contains `damage_type`
+fn has_damage_type(data: &Map<String, Value>, key: &str, damage_type: &str) -> bool {
+    match data.get(key) {
+        Some(Value::Array(types)) => types
+            .iter()
+            .filter_map(Value::as_str)
+            .any(|v| v.eq_ignore_ascii_case(damage_type)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::server::test_helpers::object;
+
+    #[test]
+    fn temp_hp_is_used_up_first() {
+        let mut data = object(json!({ "hp": 20, "hp_max": 20, "temp_hp": 5 }));
+
+        let entry = apply_damage("Goblin", &mut data, 8, None);
+
+        assert_eq!((entry.hp, entry.temp_hp, entry.applied), (17, 0, 8));
+        assert_eq!(data["hp"], 17);
+        assert_eq!(data["temp_hp"], 0);
+
+        let entry = apply_damage("Goblin", &mut data, 3, None);
+
+        assert_eq!((entry.hp, entry.temp_hp), (14, 0));
+    }
+
+    #[test]
+    fn temp_hp_can_absorb_everything() {
+        let mut data = object(json!({ "hp": 20, "temp_hp": 10 }));
+
+        let entry = apply_damage("Goblin", &mut data, 4, None);
+
+        assert_eq!((entry.hp, entry.temp_hp), (20, 6));
+    }
+
+    #[test]
+    fn resistances_vulnerabilities_and_immunities() {
+        let mut data = object(json!({
+            "hp": 30,
+            "resistances": ["Fire"],
+            "vulnerabilities": ["cold"],
+            "immunities": ["poison"],
+        }));
+
+        // Resistance rounds down
+        assert_eq!(apply_damage("Troll", &mut data, 7, Some("fire")).applied, 3);
+        assert_eq!(
+            apply_damage("Troll", &mut data, 5, Some("COLD")).applied,
+            10
+        );
+        assert_eq!(
+            apply_damage("Troll", &mut data, 50, Some("poison")).applied,
+            0
+        );
+        assert_eq!(apply_damage("Troll", &mut data, 2, Some("acid")).applied, 2);
+
+        assert_eq!(data["hp"], 30 - 3 - 10 - 2);
+    }
+
+    #[test]
+    fn hp_stops_at_zero() {
+        let mut data = object(json!({ "hp": 5, "hp_max": 10 }));
+
+        let entry = apply_damage("Kobold", &mut data, 100, None);
+
+        assert_eq!(entry.hp, 0);
+        assert_eq!(entry.applied, 100);
+    }
+
+    #[test]
+    fn missing_hp_means_full_health() {
+        let mut data = object(json!({ "hp_max": 12 }));
+
+        assert_eq!(apply_damage("Wolf", &mut data, 5, None).hp, 7);
+    }
+
+    #[test]
+    fn healing_stops_at_max_hp() {
+        let mut data = object(json!({ "hp": 4, "hp_max": 10, "temp_hp": 3 }));
+
+        let entry = apply_healing("Cleric", &mut data, 20);
+
+        assert_eq!((entry.hp, entry.applied, entry.temp_hp), (10, 6, 3));
+    }
+
+    #[test]
+    fn huge_numbers_dont_overflow() {
+        let mut data = object(json!({ "hp": i64::MIN, "vulnerabilities": ["fire"] }));
+
+        let entry = apply_damage("Dragon", &mut data, u32::MAX, Some("fire"));
+
+        assert_eq!((entry.hp, entry.applied), (0, u32::MAX));
+
+        let mut data = object(json!({ "hp": i64::MAX }));
+
+        assert_eq!(apply_healing("Dragon", &mut data, u32::MAX).hp, i64::MAX);
+        assert_eq!(apply_damage("Dragon", &mut data, 1, None).hp, i64::MAX - 1);
+    }
+
+    #[test]
+    fn healing_from_below_zero() {
+        let mut data = object(json!({ "hp": i64::MIN, "hp_max": 10 }));
+
+        // All of the healing is used, it just isn't enough to get back to 0
+        let entry = apply_healing("Zombie", &mut data, u32::MAX);
+
+        assert_eq!((entry.hp, entry.applied), (0, u32::MAX));
+    }
+}
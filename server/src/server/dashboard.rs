@@ -0,0 +1,150 @@
+use std::{sync::Arc, time::Duration};
+
+use serde_json::{Map, Value};
+use tokio::{
+    sync::{watch, RwLock},
+    time::sleep,
+};
+
+use super::{
+    damage::{get_i64, hp_of},
+    game::GameState,
+    rooms::Rooms,
+};
+
+/// How long to wait after something changes before sending the characters, so a burst of changes only gets sent once
+const UPDATE_DELAY: Duration = Duration::from_millis(250);
+
+/// What the GM's dashboard shows about a character
+#[derive(Debug, Clone, PartialEq)]
+pub struct CharacterSummary {
+    /// The campaign the character's being played in
+    pub room: String,
+    pub name: String,
+    pub owner: u32,
+    pub hp: i64,
+    pub hp_max: Option<i64>,
+    pub temp_hp: i64,
+    pub initiative: Option<i64>,
+
+    /// Whether it's the character's turn in combat
+    pub taking_turn: bool,
+
+    /// The remaining & maximum spell slots for each spell level, starting at 1st level
+    pub spell_slots: Vec<(i64, i64)>,
+
+    /// The total weight of the character's items
+    pub carried_weight: f64,
+}
+
+/// Summarize every character for the GUI whenever a room changes, until the task's aborted
+///
+/// The summaries are kept in a watch channel, so a GUI that starts listening late still gets the latest ones
+pub(super) async fn report_characters(
+    rooms: Arc<RwLock<Rooms>>,
+    sender: watch::Sender<Vec<CharacterSummary>>,
+) {
+    let changed = rooms.read().await.changed();
+
+    loop {
+        let characters = summarize(&rooms).await;
+
+        sender.send_if_modified(|old| {
+            let modified = *old != characters;
+            *old = characters;
+            modified
+        });
+
+        changed.dashboard.notified().await;
+        sleep(UPDATE_DELAY).await;
+    }
+}
+
+/// Every character in every room, sorted by room and then by name
+async fn summarize(rooms: &RwLock<Rooms>) -> Vec<CharacterSummary> {
+    let states = rooms.read().await.states();
+
+    let mut summaries = Vec::new();
+
+    for (room, state) in states {
+        let state = state.read().await;
+
+        for (name, character) in state.characters.iter() {
+            // Characters the client hasn't filled in yet still get a row, just with nothing in it
+            let data = match serde_json::from_str(&character.data) {
+                Ok(Value::Object(obj)) => obj,
+                _ => Map::new(),
+            };
+
+            summaries.push(summarize_character(
+                &room,
+                name,
+                character.owner,
+                &data,
+                &state,
+            ));
+        }
+    }
+
+    summaries.sort_by(|a, b| (&a.room, &a.name).cmp(&(&b.room, &b.name)));
+
+    summaries
+}
+
+fn summarize_character(
+    room: &str,
+    name: &str,
+    owner: u32,
+    data: &Map<String, Value>,
+    state: &GameState,
+) -> CharacterSummary {
+    let (hp, hp_max) = hp_of(data);
+
+    CharacterSummary {
+        room: room.to_owned(),
+        name: name.to_owned(),
+        owner,
+        hp,
+        hp_max,
+        temp_hp: get_i64(data, "temp_hp").unwrap_or(0).max(0),
+        initiative: get_i64(data, "initiative"),
+        taking_turn: state.initiative.current_turn() == Some(name),
+        spell_slots: spell_slots(data),
+        carried_weight: carried_weight(data),
+    }
+}
+
+/// The client keeps the maximum slots in `spellSlots` and the remaining ones in `currentSpellSlots`, one number per level
+fn spell_slots(data: &Map<String, Value>) -> Vec<(i64, i64)> {
+    let numbers = |key: &str| match data.get(key) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|v| v.as_i64().unwrap_or(0))
+            .collect::<Vec<_>>(),
+        _ => Vec::new(),
+    };
+
+    let remaining = numbers("currentSpellSlots");
+
+    numbers("spellSlots")
+        .into_iter()
+        .enumerate()
+        .map(|(level, max)| (remaining.get(level).copied().unwrap_or(0), max))
+        .collect()
+}
+
+/// Items without a weight weigh nothing, and items without a quantity count once
+fn carried_weight(data: &Map<String, Value>) -> f64 {
+    match data.get("items") {
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| {
+                let weight = item.get("unitWeight").and_then(Value::as_f64);
+                let quantity = item.get("quantity").and_then(Value::as_f64);
+
+                weight.unwrap_or(0.0) * quantity.unwrap_or(1.0)
+            })
+            .sum(),
+        _ => 0.0,
+    }
+}
@@ -0,0 +1,282 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::unix_time;
+
+/// The most dice one expression can roll, so nobody can make the server roll a billion dice
+const MAX_DICE: u32 = 1000;
+
+/// The most constants & dice one expression can have, however few dice they roll
+const MAX_FACTORS: usize = 100;
+
+/// A parsed dice expression, like `8d6`, `d20 + 5` or `2 * 3d4 - 1`
+///
+/// Uses the same syntax as the website's dice roller. It's kept as a flat list of terms that are added up,
+/// so rolling even the longest expression doesn't recurse
+#[derive(Debug, Clone)]
+pub(super) struct Dice {
+    terms: Vec<Term>,
+}
+
+/// The product of some constants & dice, which is added to or subtracted from the total
+#[derive(Debug, Clone)]
+struct Term {
+    negative: bool,
+    factors: Vec<Factor>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Factor {
+    Const(i64),
+    Roll { count: u32, sides: u32 },
+}
+
+impl Dice {
+    /// Parse a dice expression, returns None if the syntax is invalid or it rolls too many dice
+    pub fn parse(dice: &str) -> Option<Dice> {
+        let formatted = dice.to_lowercase().replace(' ', "");
+
+        let mut parser = Parser {
+            chars: formatted.as_bytes(),
+            i: 0,
+            dice: 0,
+            factors: 0,
+        };
+
+        let parsed = parser.sum()?;
+
+        // Anything left over means there was something that isn't dice syntax
+        if parser.i != formatted.len() {
+            return None;
+        }
+
+        Some(parsed)
+    }
+
+    /// A single d20, for initiative and saving throws
+    pub fn d20() -> Dice {
+        Dice {
+            terms: vec![Term {
+                negative: false,
+                factors: vec![Factor::Roll {
+                    count: 1,
+                    sides: 20,
+                }],
+            }],
+        }
+    }
+
+    /// Roll the dice
+    pub fn roll(&self) -> i64 {
+        self.roll_with(&mut rand::thread_rng())
+    }
+
+    fn roll_with(&self, rng: &mut impl Rng) -> i64 {
+        self.terms.iter().fold(0, |total: i64, term| {
+            let product = term.factors.iter().fold(1, |product: i64, factor| {
+                product.saturating_mul(factor.roll_with(rng))
+            });
+
+            if term.negative {
+                total.saturating_sub(product)
+            } else {
+                total.saturating_add(product)
+            }
+        })
+    }
+}
+
+impl Factor {
+    fn roll_with(&self, rng: &mut impl Rng) -> i64 {
+        match *self {
+            Factor::Const(v) => v,
+            Factor::Roll { count, sides } => {
+                (0..count).map(|_| rng.gen_range(1..=sides as i64)).sum()
+            }
+        }
+    }
+}
+
+/// A record of a roll the server made
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct RollLogEntry {
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+
+    /// What the roll was for, like `Fireball damage` or `Goblin 1 Dexterity save`
+    pub reason: String,
+
+    pub expression: String,
+    pub result: i64,
+}
+
+impl RollLogEntry {
+    pub fn new(reason: String, expression: &str, result: i64) -> RollLogEntry {
+        RollLogEntry {
+            timestamp: unix_time(),
+            reason,
+            expression: expression.to_owned(),
+            result,
+        }
+    }
+}
+
+/// Parses a sum of products, multiplication binds tighter than addition and subtraction
+struct Parser<'a> {
+    chars: &'a [u8],
+    i: usize,
+
+    /// How many dice & factors have been parsed so far, to stop at the limits
+    dice: u32,
+    factors: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.chars.get(self.i).copied()
+    }
+
+    fn sum(&mut self) -> Option<Dice> {
+        let mut terms = vec![Term {
+            negative: false,
+            factors: self.product()?,
+        }];
+
+        loop {
+            let negative = match self.peek() {
+                Some(b'+') => false,
+                Some(b'-') => true,
+                _ => return Some(Dice { terms }),
+            };
+
+            self.i += 1;
+
+            terms.push(Term {
+                negative,
+                factors: self.product()?,
+            });
+        }
+    }
+
+    fn product(&mut self) -> Option<Vec<Factor>> {
+        let mut factors = vec![self.factor()?];
+
+        while let Some(b'*') = self.peek() {
+            self.i += 1;
+            factors.push(self.factor()?);
+        }
+
+        Some(factors)
+    }
+
+    /// A constant like `4`, or dice like `d20` or `3d4`
+    fn factor(&mut self) -> Option<Factor> {
+        self.factors += 1;
+
+        if self.factors > MAX_FACTORS {
+            return None;
+        }
+
+        let count = self.number();
+
+        if self.peek() != Some(b'd') {
+            return Some(Factor::Const(count?.into()));
+        }
+
+        self.i += 1;
+
+        let count = count.unwrap_or(1);
+        let sides = self.number()?;
+
+        // The limit is for the whole expression, otherwise `1000d6 + 1000d6 + ...` gets around it
+        self.dice = self.dice.saturating_add(count);
+
+        if self.dice > MAX_DICE || sides == 0 {
+            return None;
+        }
+
+        Some(Factor::Roll { count, sides })
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let start = self.i;
+
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.i += 1;
+        }
+
+        std::str::from_utf8(&self.chars[start..self.i])
+            .ok()?
+            .parse()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Roll an expression that doesn't have any dice in it
+    fn constant(expression: &str) -> i64 {
+        Dice::parse(expression).unwrap().roll()
+    }
+
+    #[test]
+    fn constants_follow_precedence() {
+        assert_eq!(constant("4"), 4);
+        assert_eq!(constant("1 + 2 * 3"), 7);
+        assert_eq!(constant("2 * 3 - 1"), 5);
+        assert_eq!(constant("10 - 2 - 3"), 5);
+    }
+
+    #[test]
+    fn rolls_stay_in_range() {
+        let dice = Dice::parse("3D4 + 1").unwrap();
+
+        for _ in 0..1000 {
+            assert!((4..=13).contains(&dice.roll()));
+        }
+
+        let d20 = Dice::parse("d20").unwrap();
+
+        for _ in 0..1000 {
+            assert!((1..=20).contains(&d20.roll()));
+        }
+    }
+
+    #[test]
+    fn invalid_syntax_is_rejected() {
+        for expression in ["", "d", "2d", "d0", "2d6x", "+1", "1 +", "3 ** 2", "1001d6"] {
+            assert!(
+                Dice::parse(expression).is_none(),
+                "`{}` should be invalid",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn the_most_dice_can_be_rolled() {
+        assert!(Dice::parse("1000d6").is_some());
+        assert!(Dice::parse("500d6 + 500d6").is_some());
+    }
+
+    #[test]
+    fn the_dice_limit_is_for_the_whole_expression() {
+        assert!(Dice::parse("600d6 + 600d6").is_none());
+        assert!(Dice::parse("1000d6 * 1d6").is_none());
+    }
+
+    #[test]
+    fn long_expressions_are_rejected() {
+        let longest = vec!["1"; MAX_FACTORS].join("+");
+        assert_eq!(constant(&longest), MAX_FACTORS as i64);
+
+        let too_long = vec!["1"; MAX_FACTORS + 1].join("+");
+        assert!(Dice::parse(&too_long).is_none());
+
+        // Used to overflow the stack when it was parsed into nested boxes
+        let huge = vec!["1"; 1_000_000].join("+");
+        assert!(Dice::parse(&huge).is_none());
+    }
+}
@@ -0,0 +1,82 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::utils::next_free_port;
+
+/// Why the server couldn't start, or why it stopped when it wasn't told to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerError {
+    /// Something else is already listening on the port
+    PortInUse {
+        port: u16,
+
+        /// A port close by that's free, if there is one
+        free_port: Option<u16>,
+    },
+
+    /// The OS won't let the app listen on the port, ports below 1024 usually need admin rights
+    PermissionDenied { port: u16 },
+
+    /// The address to listen on doesn't belong to this computer
+    AddressUnavailable { address: IpAddr },
+
+    /// Anything else, with the error's own message
+    Other { message: String },
+}
+
+impl ServerError {
+    /// Work out what went wrong when listening on an address failed
+    pub(super) fn from_bind(error: &io::Error, addr: SocketAddr) -> ServerError {
+        match error.kind() {
+            ErrorKind::AddrInUse => ServerError::PortInUse {
+                port: addr.port(),
+                free_port: next_free_port(addr.ip(), addr.port()),
+            },
+            ErrorKind::PermissionDenied => ServerError::PermissionDenied { port: addr.port() },
+            ErrorKind::AddrNotAvailable => ServerError::AddressUnavailable { address: addr.ip() },
+            _ => ServerError::Other {
+                message: error.to_string(),
+            },
+        }
+    }
+
+    /// What the GM could do to fix the error, if there's anything obvious
+    pub fn suggestion(&self) -> Option<String> {
+        match self {
+            ServerError::PortInUse {
+                free_port: Some(free_port),
+                ..
+            } => Some(format!("Port {} is free, try that one instead", free_port)),
+            ServerError::PortInUse {
+                free_port: None, ..
+            } => Some("Close whatever's using the port, or pick a different one".to_owned()),
+            ServerError::PermissionDenied { .. } => {
+                Some("Pick a port above 1024, like 8000".to_owned())
+            }
+            ServerError::AddressUnavailable { .. } => {
+                Some("Listen on every address (0.0.0.0) instead".to_owned())
+            }
+            ServerError::Other { .. } => None,
+        }
+    }
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::PortInUse { port, .. } => {
+                write!(f, "Something else is already using port {}", port)
+            }
+            ServerError::PermissionDenied { port } => {
+                write!(f, "The server isn't allowed to use port {}", port)
+            }
+            ServerError::AddressUnavailable { address } => {
+                write!(f, "{} isn't an address of this computer", address)
+            }
+            ServerError::Other { message } => write!(f, "The server threw an error: {}", message),
+        }
+    }
+}
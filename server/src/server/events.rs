@@ -0,0 +1,510 @@
+use std::{
+    collections::HashMap,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::{error, warn};
+
+use crate::utils::unix_time;
+
+use super::{
+    conditions::Condition,
+    damage::DamageLogEntry,
+    dice::RollLogEntry,
+    game::{CharacterState, GameState},
+    storage::Storage,
+    templates::NpcTemplate,
+    visibility::Visibility,
+    Role,
+};
+
+/// A change to a room that the server accepted
+///
+/// Folding every event for a room in order gives the room's state, so events record the outcome of anything random
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) enum GameEvent {
+    CharacterAdded {
+        name: String,
+        owner: u32,
+        data: String,
+        visibility: Visibility,
+    },
+    CharacterChanged {
+        name: String,
+        owner: u32,
+        data: String,
+
+        /// The id of whoever made the change
+        author: u32,
+    },
+    CharacterRemoved {
+        name: String,
+    },
+    VisibilityChanged {
+        name: String,
+        visibility: Visibility,
+    },
+    ConditionsChanged {
+        name: String,
+        conditions: Vec<Condition>,
+    },
+    Damaged {
+        entry: DamageLogEntry,
+    },
+    Rolled {
+        entry: RollLogEntry,
+    },
+    CombatStarted,
+    TurnAdvanced,
+    CombatEnded,
+    JoinedCombat {
+        name: String,
+    },
+    TemplateSaved {
+        template: NpcTemplate,
+    },
+    TemplateDeleted {
+        name: String,
+    },
+    PlayerJoined {
+        id: u32,
+        role: Role,
+    },
+    PlayerLeft {
+        id: u32,
+    },
+
+    /// The room was replaced with a backup
+    ///
+    /// A checkpoint is saved right after, so the backup itself doesn't need to be in the log
+    Restored,
+}
+
+/// An event as it's written to the log
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct LoggedEvent {
+    /// Counts up from 1 across every room, so snapshots can tell which events they already include
+    pub sequence: u64,
+
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+
+    pub room: String,
+    pub event: GameEvent,
+}
+
+/// How many events can be logged between checkpoints, so loading and replaying never have to read more than this many events past a snapshot
+const EVENTS_PER_CHECKPOINT: u64 = 10_000;
+
+/// The append-only log of every event, shared by every room
+///
+/// Events are written by their own thread, so rooms don't wait on the disk while they're locked. Events that can't be written are reported and dropped, so the game keeps working without the log
+#[derive(Debug)]
+pub(super) struct EventLog {
+    storage: Arc<dyn Storage>,
+
+    /// Also held while handing events to the writer, so they're written in the order they're numbered
+    sequence: Mutex<Sequence>,
+
+    writer: mpsc::Sender<LogWrite>,
+}
+
+/// What the log's writer thread is asked to do, in the order it's asked
+#[derive(Debug)]
+enum LogWrite {
+    Append(LoggedEvent),
+
+    /// Start a new part of the log for the events after the sequence number
+    Rotate(u64),
+
+    /// Say when everything asked for before has been written
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug)]
+struct Sequence {
+    next: u64,
+
+    /// The last event before the log was last rotated
+    rotated_at: u64,
+}
+
+/// Lets a room's state add events to the log
+#[derive(Debug, Clone)]
+pub(super) struct EventRecorder {
+    pub room: String,
+    pub log: Arc<EventLog>,
+}
+
+impl EventLog {
+    /// Open the log in the storage for appending, the next event is numbered after `last_sequence`
+    ///
+    /// `checkpointed` is the last event the newest snapshot includes, the next checkpoint is counted from there
+    pub fn open(storage: Arc<dyn Storage>, last_sequence: u64, checkpointed: u64) -> EventLog {
+        let (writer, writes) = mpsc::channel();
+
+        // The thread stops once the log's dropped and everything it was sent is written
+        let writer_storage = Arc::clone(&storage);
+        thread::spawn(move || write_log(&*writer_storage, writes));
+
+        EventLog {
+            storage,
+            sequence: Mutex::new(Sequence {
+                next: last_sequence + 1,
+                rotated_at: checkpointed,
+            }),
+            writer,
+        }
+    }
+
+    /// Where the log and everything else about the game is kept
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// Have an event written to the end of the log, returning its sequence number
+    fn append(&self, room: &str, event: GameEvent) -> u64 {
+        let mut counter = self.sequence.lock().unwrap();
+
+        let sequence = counter.next;
+        counter.next += 1;
+
+        let logged = LoggedEvent {
+            sequence,
+            timestamp: unix_time(),
+            room: room.to_owned(),
+            event,
+        };
+
+        self.write(LogWrite::Append(logged));
+
+        sequence
+    }
+
+    fn write(&self, write: LogWrite) {
+        if self.writer.send(write).is_err() {
+            error!("Couldn't write to the event log, its writer stopped");
+        }
+    }
+
+    /// Wait until everything that's been logged so far is written
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+
+        self.write(LogWrite::Flush(done));
+
+        written.await.ok();
+    }
+
+    /// The sequence number of the last event that's been logged
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence.lock().unwrap().next - 1
+    }
+
+    /// The sequence number of the last event, a snapshot taken after calling this includes every event up to it
+    ///
+    /// If it's been long enough since the last checkpoint, or `force` is set, the log is rotated there too and true is returned, so the snapshot should be kept as a checkpoint
+    pub fn mark_snapshot(&self, force: bool) -> (u64, bool) {
+        let mut counter = self.sequence.lock().unwrap();
+
+        let last = counter.next - 1;
+
+        if !force && last < counter.rotated_at + EVENTS_PER_CHECKPOINT {
+            return (last, false);
+        }
+
+        counter.rotated_at = last;
+
+        // Still holding the counter, so every event up to `last` is written before the log is rotated and every event after it is written after
+        self.write(LogWrite::Rotate(last));
+
+        (last, true)
+    }
+}
+
+/// Write everything the log's sent to the storage, until the log's dropped
+fn write_log(storage: &dyn Storage, writes: mpsc::Receiver<LogWrite>) {
+    for write in writes {
+        match write {
+            LogWrite::Append(logged) => {
+                if let Err(e) = storage.append_event(&logged) {
+                    error!("Couldn't write to the event log: {}", e);
+                }
+            }
+
+            LogWrite::Rotate(sequence) => {
+                if let Err(e) = storage.rotate_events(sequence) {
+                    error!("Couldn't start a new part of the event log: {}", e);
+                }
+            }
+
+            LogWrite::Flush(done) => {
+                done.send(()).ok();
+            }
+        }
+    }
+}
+
+/// Read the events in the log after the given sequence number, oldest first
+pub(super) fn read_log_after(storage: &dyn Storage, sequence: u64) -> Vec<LoggedEvent> {
+    match storage.read_events_after(sequence) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Couldn't read the event log: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Rebuild every room as it was at the given time, by folding the log on top of a snapshot that includes every event up to `sequence`
+///
+/// Events a room already includes are skipped, since rooms can be snapshotted a little after `sequence`
+pub(super) fn replay_until(
+    storage: &dyn Storage,
+    mut states: HashMap<String, GameState>,
+    sequence: u64,
+    timestamp: u64,
+) -> HashMap<String, GameState> {
+    for logged in read_log_after(storage, sequence) {
+        if logged.timestamp > timestamp {
+            break;
+        }
+
+        let state = states.entry(logged.room).or_default();
+
+        if logged.sequence > state.last_event {
+            state.apply(logged.event, logged.timestamp);
+            state.last_event = logged.sequence;
+        }
+    }
+
+    states
+}
+
+impl GameState {
+    /// Add an event to the log, if the state is in a room that's recording
+    pub fn record(&mut self, event: GameEvent) {
+        if let Some(recorder) = &self.recorder {
+            self.last_event = recorder.log.append(&recorder.room, event);
+        }
+    }
+
+    /// Change the state the way the event says, this is how the state is rebuilt from the log
+    ///
+    /// The timestamp is when the event was logged, so characters' histories keep the times they really changed
+    pub fn apply(&mut self, event: GameEvent, timestamp: u64) {
+        match event {
+            GameEvent::CharacterAdded {
+                name,
+                owner,
+                data,
+                visibility,
+            } => {
+                self.characters.insert(
+                    name,
+                    CharacterState::created_at(owner, data, visibility, timestamp),
+                );
+            }
+
+            GameEvent::CharacterChanged {
+                name,
+                owner,
+                data,
+                author,
+            } => {
+                if let Some(character) = self.characters.get_mut(&name) {
+                    character.owner = owner;
+                    character.set_data_at(data, author, timestamp);
+                }
+            }
+
+            GameEvent::CharacterRemoved { name } => {
+                self.remove_character(&name);
+            }
+
+            GameEvent::VisibilityChanged { name, visibility } => {
+                if let Some(character) = self.characters.get_mut(&name) {
+                    character.visibility = visibility;
+                }
+            }
+
+            GameEvent::ConditionsChanged { name, conditions } => {
+                if let Some(character) = self.characters.get_mut(&name) {
+                    character.conditions = conditions;
+                }
+            }
+
+            GameEvent::Damaged { entry } => self.damage_log.push(entry),
+            GameEvent::Rolled { entry } => self.roll_log.push(entry),
+
+            GameEvent::CombatStarted => {
+                self.start_combat();
+            }
+            GameEvent::TurnAdvanced => {
+                self.next_turn();
+            }
+            GameEvent::CombatEnded => {
+                self.end_combat();
+            }
+            GameEvent::JoinedCombat { name } => self.join_combat(&name),
+
+            GameEvent::TemplateSaved { template } => {
+                self.templates.insert(template.name.clone(), template);
+            }
+            GameEvent::TemplateDeleted { name } => {
+                self.templates.remove(&name);
+            }
+
+            // Connections don't change the game, they're only logged so sessions can be looked back on
+            GameEvent::PlayerJoined { .. } | GameEvent::PlayerLeft { .. } => {}
+
+            GameEvent::Restored => {
+                warn!("Skipping a restore that's only in a checkpoint");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::MemoryStorage;
+
+    fn logged(sequence: u64, timestamp: u64, room: &str, event: GameEvent) -> LoggedEvent {
+        LoggedEvent {
+            sequence,
+            timestamp,
+            room: room.to_owned(),
+            event,
+        }
+    }
+
+    fn added(name: &str) -> GameEvent {
+        GameEvent::CharacterAdded {
+            name: name.to_owned(),
+            owner: 1,
+            data: format!(r#"{{"name":"{}"}}"#, name),
+            visibility: Visibility::Revealed,
+        }
+    }
+
+    #[test]
+    fn applying_events_rebuilds_the_state() {
+        let mut state = GameState::default();
+
+        state.apply(added("Goblin"), 100);
+        state.apply(added("Orc"), 100);
+        state.apply(
+            GameEvent::CharacterChanged {
+                name: "Goblin".to_owned(),
+                owner: 2,
+                data: r#"{"name":"Goblin","hp":3}"#.to_owned(),
+                author: 2,
+            },
+            200,
+        );
+        state.apply(
+            GameEvent::VisibilityChanged {
+                name: "Orc".to_owned(),
+                visibility: Visibility::Hidden,
+            },
+            200,
+        );
+        state.apply(
+            GameEvent::CharacterRemoved {
+                name: "Orc".to_owned(),
+            },
+            300,
+        );
+
+        assert_eq!(state.characters.len(), 1);
+        assert_eq!(state.characters["Goblin"].owner, 2);
+        assert_eq!(
+            state.characters["Goblin"].data,
+            r#"{"name":"Goblin","hp":3}"#
+        );
+
+        // The history says when the changes were logged, not when they were replayed
+        let history = &state.characters["Goblin"].history;
+        assert_eq!(
+            history.iter().map(|v| v.timestamp).collect::<Vec<_>>(),
+            [100, 200]
+        );
+    }
+
+    #[test]
+    fn changes_to_missing_characters_are_ignored() {
+        let mut state = GameState::default();
+
+        state.apply(
+            GameEvent::CharacterChanged {
+                name: "Ghost".to_owned(),
+                owner: 1,
+                data: "{}".to_owned(),
+                author: 1,
+            },
+            100,
+        );
+
+        assert!(state.characters.is_empty());
+    }
+
+    #[test]
+    fn replaying_stops_at_the_time() {
+        let storage = MemoryStorage::default();
+
+        for event in [
+            logged(1, 100, "a", added("Goblin")),
+            logged(2, 200, "b", added("Wizard")),
+            logged(3, 300, "a", added("Orc")),
+        ] {
+            storage.append_event(&event).unwrap();
+        }
+
+        let states = replay_until(&storage, HashMap::new(), 0, 250);
+
+        assert_eq!(states["a"].characters.len(), 1);
+        assert_eq!(states["a"].last_event, 1);
+        assert_eq!(states["b"].last_event, 2);
+    }
+
+    #[test]
+    fn replaying_skips_events_the_snapshot_has() {
+        let storage = MemoryStorage::default();
+
+        for event in [
+            logged(1, 100, "a", added("Goblin")),
+            logged(2, 100, "a", added("Orc")),
+            logged(
+                3,
+                100,
+                "a",
+                GameEvent::CharacterRemoved {
+                    name: "Goblin".to_owned(),
+                },
+            ),
+        ] {
+            storage.append_event(&event).unwrap();
+        }
+
+        // The snapshot was marked after the first event, but the room had the second one by the time it was taken
+        let mut snapshot = GameState::default();
+        snapshot.apply(added("Goblin"), 100);
+        snapshot.apply(added("Orc"), 100);
+        snapshot.last_event = 2;
+
+        let states = replay_until(
+            &storage,
+            HashMap::from([("a".to_owned(), snapshot)]),
+            1,
+            u64::MAX,
+        );
+
+        assert_eq!(states["a"].characters.len(), 1);
+        assert!(states["a"].characters.contains_key("Orc"));
+        assert_eq!(states["a"].last_event, 3);
+    }
+}
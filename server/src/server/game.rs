@@ -0,0 +1,322 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::utils::unix_time;
+
+use super::{
+    conditions::{tick_conditions, Condition, TurnBoundary},
+    damage::DamageLogEntry,
+    dice::RollLogEntry,
+    events::EventRecorder,
+    history::CharacterVersion,
+    initiative::Initiative,
+    templates::NpcTemplate,
+    visibility::Visibility,
+};
+
+/// Everything the server knows about the game being played
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(super) struct GameState {
+    /// Characters by name
+    pub characters: HashMap<String, CharacterState>,
+    pub initiative: Initiative,
+
+    /// Every bit of damage & healing the server applied, oldest first
+    pub damage_log: Vec<DamageLogEntry>,
+
+    /// Every roll the server made, oldest first
+    pub roll_log: Vec<RollLogEntry>,
+
+    /// Preset NPCs by name
+    pub templates: HashMap<String, NpcTemplate>,
+
+    /// The sequence number of the last event in the log that the state includes
+    pub last_event: u64,
+
+    /// Where changes to the state get logged, None while it's being rebuilt from the log
+    #[serde(skip)]
+    pub recorder: Option<EventRecorder>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct CharacterState {
+    /// The id of the player who owns the character
+    pub owner: u32,
+
+    /// The character's data, encoded as JSON by the client
+    pub data: String,
+
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Recent versions of the character's data, oldest first
+    #[serde(default)]
+    pub history: VecDeque<CharacterVersion>,
+}
+
+/// What happened when the turn changed
+#[derive(Debug, Default)]
+pub(super) struct TurnOutcome {
+    pub round: u32,
+    pub character: Option<String>,
+
+    /// Conditions that expired, and the name of the character they were on
+    pub expired: Vec<(String, Condition)>,
+}
+
+impl CharacterState {
+    pub fn new(owner: u32, data: String, visibility: Visibility) -> CharacterState {
+        CharacterState::created_at(owner, data, visibility, unix_time())
+    }
+
+    /// Make a character as of a time, for characters being rebuilt from the log
+    pub fn created_at(
+        owner: u32,
+        data: String,
+        visibility: Visibility,
+        timestamp: u64,
+    ) -> CharacterState {
+        let mut character = CharacterState {
+            owner,
+            data,
+            conditions: Vec::new(),
+            visibility,
+            history: VecDeque::new(),
+        };
+
+        character.record_version(owner, timestamp);
+
+        character
+    }
+
+    /// Edit the character's data as a JSON object, returns None if the data isn't an object
+    pub fn edit_data<T>(
+        &mut self,
+        author: u32,
+        edit: impl FnOnce(&mut Map<String, Value>) -> T,
+    ) -> Option<T> {
+        let mut obj = match serde_json::from_str(&self.data) {
+            Ok(Value::Object(obj)) => obj,
+            _ => return None,
+        };
+
+        let result = edit(&mut obj);
+
+        self.set_data(Value::Object(obj).to_string(), author);
+
+        Some(result)
+    }
+
+    /// The character's initiative, if the client has set it
+    pub fn initiative(&self) -> Option<i64> {
+        match serde_json::from_str(&self.data) {
+            Ok(Value::Object(obj)) => obj.get("initiative")?.as_i64(),
+            _ => None,
+        }
+    }
+}
+
+impl GameState {
+    /// Start combat with every character that has rolled initiative, from highest to lowest initiative
+    pub fn start_combat(&mut self) -> TurnOutcome {
+        let mut order = self
+            .characters
+            .iter()
+            .filter_map(|(name, character)| Some((character.initiative()?, name.clone())))
+            .collect::<Vec<_>>();
+
+        // Ties are broken by name so everyone sees the same order
+        order.sort_by(|(a_initiative, a_name), (b_initiative, b_name)| {
+            turn_order(*a_initiative, a_name, *b_initiative, b_name)
+        });
+
+        self.initiative
+            .start(order.into_iter().map(|(_, name)| name).collect());
+
+        let mut outcome = self.turn_outcome();
+
+        if let Some(started) = &outcome.character {
+            outcome.expired = self.tick(TurnBoundary::Start(started));
+        }
+
+        outcome
+    }
+
+    /// End the current character's turn and start the next one's, ticking conditions on the way
+    pub fn next_turn(&mut self) -> TurnOutcome {
+        let (ended, started) = match self.initiative.next_turn() {
+            Some(v) => v,
+            None => return self.turn_outcome(),
+        };
+
+        let mut expired = self.tick(TurnBoundary::End(&ended));
+        expired.append(&mut self.tick(TurnBoundary::Start(&started)));
+
+        TurnOutcome {
+            expired,
+            ..self.turn_outcome()
+        }
+    }
+
+    pub fn end_combat(&mut self) -> TurnOutcome {
+        self.initiative.end();
+
+        self.turn_outcome()
+    }
+
+    /// Add a character to combat that's already going on, in the right place for its initiative
+    ///
+    /// Does nothing if there's no combat or the character hasn't rolled initiative
+    pub fn join_combat(&mut self, name: &str) {
+        if self.initiative.current.is_none() {
+            return;
+        }
+
+        let initiative = match self.characters.get(name).and_then(|v| v.initiative()) {
+            Some(v) => v,
+            None => return,
+        };
+
+        let index = self
+            .initiative
+            .order
+            .iter()
+            .position(|other| {
+                let other_initiative = self
+                    .characters
+                    .get(other)
+                    .and_then(|v| v.initiative())
+                    .unwrap_or(i64::MIN);
+
+                turn_order(initiative, name, other_initiative, other).is_lt()
+            })
+            .unwrap_or(self.initiative.order.len());
+
+        self.initiative.insert(index, name.to_owned());
+    }
+
+    /// Remove a character from the game, along with its place in the initiative order
+    pub fn remove_character(&mut self, name: &str) -> Option<CharacterState> {
+        let removed = self.characters.remove(name);
+        self.initiative.remove(name);
+
+        removed
+    }
+
+    fn turn_outcome(&self) -> TurnOutcome {
+        TurnOutcome {
+            round: self.initiative.round,
+            character: self.initiative.current_turn().map(str::to_owned),
+            expired: Vec::new(),
+        }
+    }
+
+    /// Tick the conditions on every character
+    fn tick(&mut self, boundary: TurnBoundary) -> Vec<(String, Condition)> {
+        let mut expired = Vec::new();
+
+        for (name, character) in self.characters.iter_mut() {
+            for condition in tick_conditions(&mut character.conditions, boundary) {
+                expired.push((name.clone(), condition));
+            }
+        }
+
+        expired
+    }
+}
+
+/// Whether character a goes before character b, higher initiative goes first and ties are broken by name
+fn turn_order(a_initiative: i64, a_name: &str, b_initiative: i64, b_name: &str) -> Ordering {
+    b_initiative
+        .cmp(&a_initiative)
+        .then_with(|| a_name.cmp(b_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{
+        conditions::{Duration, Expiry},
+        test_helpers::{character, condition},
+    };
+
+    fn with_initiatives(characters: &[(&str, Option<i64>)]) -> GameState {
+        let mut state = GameState::default();
+
+        for (name, initiative) in characters {
+            state
+                .characters
+                .insert(name.to_string(), character(name, *initiative));
+        }
+
+        state
+    }
+
+    #[test]
+    fn combat_goes_from_highest_initiative_and_ties_go_by_name() {
+        let mut state = with_initiatives(&[
+            ("Goblin", Some(12)),
+            ("Wizard", Some(18)),
+            ("Bard", Some(12)),
+            ("Cat", None),
+        ]);
+
+        let outcome = state.start_combat();
+
+        assert_eq!(state.initiative.order, ["Wizard", "Bard", "Goblin"]);
+        assert_eq!(outcome.character.as_deref(), Some("Wizard"));
+        assert_eq!(outcome.round, 1);
+    }
+
+    #[test]
+    fn joining_combat_goes_in_initiative_order() {
+        let mut state = with_initiatives(&[("Wizard", Some(18)), ("Goblin", Some(5))]);
+        state.start_combat();
+        state.next_turn();
+
+        state
+            .characters
+            .insert("Rogue".to_owned(), character("Rogue", Some(10)));
+        state.join_combat("Rogue");
+
+        assert_eq!(state.initiative.order, ["Wizard", "Rogue", "Goblin"]);
+        assert_eq!(state.initiative.current_turn(), Some("Goblin"));
+    }
+
+    #[test]
+    fn conditions_expire_as_turns_pass() {
+        let mut state = with_initiatives(&[("Wizard", Some(18)), ("Goblin", Some(5))]);
+
+        state
+            .characters
+            .get_mut("Goblin")
+            .unwrap()
+            .conditions
+            .push(condition(
+                "Frightened",
+                Duration::Rounds(1),
+                Expiry::EndOfTurn("Wizard".to_owned()),
+            ));
+
+        state.start_combat();
+
+        let outcome = state.next_turn();
+
+        assert_eq!(outcome.character.as_deref(), Some("Goblin"));
+        assert_eq!(outcome.expired.len(), 1);
+        assert_eq!(outcome.expired[0].0, "Goblin");
+        assert_eq!(outcome.expired[0].1.name, "Frightened");
+        assert!(state.characters["Goblin"].conditions.is_empty());
+
+        assert!(state.next_turn().expired.is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+use crate::utils::unix_time;
+
+use super::game::CharacterState;
+
+/// How many versions of each character are kept, older ones are forgotten
+const MAX_HISTORY: usize = 50;
+
+/// A version of a character's data, the newest version is the character's current data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct CharacterVersion {
+    /// Counts up from 1 each time the character changes
+    pub version: u32,
+
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+
+    /// The id of whoever made the change
+    pub author: u32,
+
+    pub data: String,
+}
+
+impl CharacterState {
+    /// Replace the character's data, remembering the old version
+    pub fn set_data(&mut self, data: String, author: u32) {
+        self.set_data_at(data, author, unix_time());
+    }
+
+    /// Replace the character's data as of a time, for changes being rebuilt from the log
+    pub fn set_data_at(&mut self, data: String, author: u32, timestamp: u64) {
+        // Clients send their characters again when they reconnect, which shouldn't count as a change
+        if data == self.data && !self.history.is_empty() {
+            return;
+        }
+
+        self.data = data;
+        self.record_version(author, timestamp);
+    }
+
+    /// Add the character's current data to its history, as of `timestamp` in seconds since the unix epoch
+    pub fn record_version(&mut self, author: u32, timestamp: u64) {
+        let version = self.history.back().map_or(1, |v| v.version + 1);
+
+        self.history.push_back(CharacterVersion {
+            version,
+            timestamp,
+            author,
+            data: self.data.clone(),
+        });
+
+        if self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    /// Go back to an old version of the character, returns None if it's been forgotten
+    ///
+    /// Undoing is a change too, so it can be undone as well
+    pub fn undo_to(&mut self, version: u32, author: u32) -> Option<()> {
+        let data = self
+            .history
+            .iter()
+            .find(|v| v.version == version)?
+            .data
+            .clone();
+
+        self.set_data(data, author);
+
+        Some(())
+    }
+}
@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+/// Keeps track of whose turn it is during combat
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct Initiative {
+    /// The round of combat, starting at 1
+    pub round: u32,
+
+    /// The names of the characters in the order they take their turns
+    pub order: Vec<String>,
+
+    /// The index in `order` of the character whose turn it is, None if there's no combat going on
+    pub current: Option<usize>,
+}
+
+impl Initiative {
+    /// Start combat with the given turn order, returning whose turn it is first
+    pub fn start(&mut self, order: Vec<String>) -> Option<&str> {
+        self.order = order;
+        self.round = 1;
+        self.current = if self.order.is_empty() { None } else { Some(0) };
+
+        self.current_turn()
+    }
+
+    /// Stop combat
+    pub fn end(&mut self) {
+        self.order.clear();
+        self.round = 0;
+        self.current = None;
+    }
+
+    /// The name of the character whose turn it is
+    pub fn current_turn(&self) -> Option<&str> {
+        self.current.map(|i| self.order[i].as_str())
+    }
+
+    /// Move on to the next character's turn, going on to the next round after the last character
+    ///
+    /// Returns the character whose turn ended and the character whose turn started
+    pub fn next_turn(&mut self) -> Option<(String, String)> {
+        let current = self.current?;
+
+        let ended = self.order[current].clone();
+
+        let next = if current + 1 >= self.order.len() {
+            self.round += 1;
+            0
+        } else {
+            current + 1
+        };
+
+        self.current = Some(next);
+
+        Some((ended, self.order[next].clone()))
+    }
+
+    /// Add a character to the turn order at the given position, without changing whose turn it is
+    pub fn insert(&mut self, index: usize, name: String) {
+        self.order.insert(index, name);
+
+        if let Some(current) = self.current.as_mut() {
+            if *current >= index {
+                *current += 1;
+            }
+        }
+    }
+
+    /// Take a character out of the turn order, the turn moves on to the next character if it was theirs
+    pub fn remove(&mut self, name: &str) {
+        let index = match self.order.iter().position(|v| v == name) {
+            Some(v) => v,
+            None => return,
+        };
+
+        self.order.remove(index);
+
+        self.current = match self.current {
+            _ if self.order.is_empty() => None,
+            Some(current) if current > index => Some(current - 1),
+            Some(current) if current >= self.order.len() => {
+                self.round += 1;
+                Some(0)
+            }
+            current => current,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_helpers::names;
+
+    fn started(order: &[&str]) -> Initiative {
+        let mut initiative = Initiative::default();
+        initiative.start(names(order));
+
+        initiative
+    }
+
+    #[test]
+    fn turns_wrap_around_to_the_next_round() {
+        let mut initiative = started(&["Fighter", "Goblin"]);
+
+        assert_eq!(initiative.current_turn(), Some("Fighter"));
+        assert_eq!(
+            initiative.next_turn(),
+            Some(("Fighter".to_owned(), "Goblin".to_owned()))
+        );
+        assert_eq!(initiative.round, 1);
+
+        initiative.next_turn();
+
+        assert_eq!(initiative.current_turn(), Some("Fighter"));
+        assert_eq!(initiative.round, 2);
+    }
+
+    #[test]
+    fn no_combat_means_no_turns() {
+        let mut initiative = started(&[]);
+
+        assert_eq!(initiative.current_turn(), None);
+        assert_eq!(initiative.next_turn(), None);
+    }
+
+    #[test]
+    fn inserting_keeps_whose_turn_it_is() {
+        let mut initiative = started(&["Fighter", "Goblin"]);
+        initiative.next_turn();
+
+        initiative.insert(0, "Rogue".to_owned());
+
+        assert_eq!(initiative.current_turn(), Some("Goblin"));
+        assert_eq!(initiative.order, ["Rogue", "Fighter", "Goblin"]);
+    }
+
+    #[test]
+    fn removing_the_last_character_moves_to_the_next_round() {
+        let mut initiative = started(&["Fighter", "Goblin"]);
+        initiative.next_turn();
+
+        initiative.remove("Goblin");
+
+        assert_eq!(initiative.current_turn(), Some("Fighter"));
+        assert_eq!(initiative.round, 2);
+
+        initiative.remove("Fighter");
+
+        assert_eq!(initiative.current_turn(), None);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fs,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::utils::{get_data_dir, write_atomically};
+
+/// What `/api/status` calls the app, so a probe can tell it's talking to another copy of this server
+pub(super) const APP_NAME: &str = "dnd-stuff";
+
+/// How long to wait for another server to answer before deciding it isn't running
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A server that's running from the same data directory, found through its lock file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunningInstance {
+    pub pid: u32,
+
+    /// Where the server can be reached from this computer
+    pub address: IpAddr,
+    pub port: u16,
+
+    /// Made up each time a server starts, so a server that restarted isn't mistaken for the one before it in the same process
+    #[serde(default)]
+    pub start: u64,
+}
+
+/// What `/api/status` responds with
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct StatusResponse {
+    pub app: String,
+    pub version: String,
+    pub pid: u32,
+}
+
+impl RunningInstance {
+    /// Where to open the server in a browser
+    pub fn url(&self) -> String {
+        format!("http://{}", SocketAddr::from((self.address, self.port)))
+    }
+}
+
+impl StatusResponse {
+    pub fn current() -> StatusResponse {
+        StatusResponse {
+            app: APP_NAME.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            pid: std::process::id(),
+        }
+    }
+}
+
+fn lock_path() -> PathBuf {
+    get_data_dir().join("server.lock")
+}
+
+/// Find another server using the same data directory, if one's running
+///
+/// The lock file says where it should be, and asking it for its status makes sure it's really still running and the lock isn't left over from a crash
+pub fn running_instance() -> Option<RunningInstance> {
+    let raw = fs::read(lock_path()).ok()?;
+    let instance = serde_json::from_slice::<RunningInstance>(&raw).ok()?;
+
+    if instance.pid == std::process::id() {
+        return None;
+    }
+
+    let status = probe(SocketAddr::from((instance.address, instance.port)))?;
+
+    (status.app == APP_NAME && status.pid == instance.pid).then_some(instance)
+}
+
+/// Ask the server at the address for its status
+fn probe(addr: SocketAddr) -> Option<StatusResponse> {
+    let mut stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).ok()?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT)).ok()?;
+
+    write!(
+        stream,
+        "GET /api/status HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        addr
+    )
+    .ok()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+
+    let (_, body) = response.split_once("\r\n\r\n")?;
+
+    serde_json::from_str(body).ok()
+}
+
+/// Say that this server is running, so other copies can find it
+///
+/// Returns the lock that was written, so only that lock is removed when the server stops
+pub(super) fn write_lock(addr: SocketAddr) -> RunningInstance {
+    // A server listening on every address can be reached on the loopback address
+    let address = if addr.ip().is_unspecified() {
+        match addr.ip() {
+            IpAddr::V4(_) => IpAddr::from([127, 0, 0, 1]),
+            IpAddr::V6(_) => IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]),
+        }
+    } else {
+        addr.ip()
+    };
+
+    let lock = RunningInstance {
+        pid: std::process::id(),
+        address,
+        port: addr.port(),
+        start: rand::random(),
+    };
+
+    if let Err(e) = write_atomically(&lock_path(), &serde_json::to_vec(&lock).unwrap()) {
+        warn!("Couldn't write the lock file: {}", e);
+    }
+
+    lock
+}
+
+/// Say that this server stopped, unless the lock's been replaced since, by a different copy or by the server that started after this one
+pub(super) fn remove_lock(written: &RunningInstance) {
+    let path = lock_path();
+
+    let ours = fs::read(&path)
+        .ok()
+        .and_then(|raw| serde_json::from_slice::<RunningInstance>(&raw).ok())
+        .is_some_and(|lock| lock == *written);
+
+    if ours {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Couldn't remove the lock file: {}", e);
+        }
+    }
+}
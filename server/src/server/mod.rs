@@ -1,5 +1,38 @@
+mod api;
+mod area_effect;
+mod conditions;
+mod config;
+mod damage;
+mod dashboard;
+mod dice;
+mod error;
+mod events;
+mod game;
+mod history;
+mod initiative;
+mod instance;
+mod moderation;
+mod persistence;
+mod players;
+mod rate_limit;
+mod rooms;
+#[allow(clippy::module_inception)]
 mod server;
 mod server_interop;
+mod session;
+mod storage;
+mod sync;
+mod templates;
+#[cfg(test)]
+mod test_helpers;
+mod visibility;
 mod websocket;
 
+pub use config::{Config, GracePeriods, LoggingConfig, Port, RateLimits, RoomsConfig};
+pub use dashboard::CharacterSummary;
+pub use error::ServerError;
+pub use instance::{running_instance, RunningInstance};
+pub use persistence::replay;
 pub use server_interop::*;
+pub use session::{format_pin, PIN_DIGITS};
+pub use storage::StorageKind;
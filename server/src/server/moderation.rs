@@ -0,0 +1,148 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+/// What the GM can do to a connection from the GUI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Action {
+    Kick,
+
+    /// Kick them, and don't let their player id or IP address back in until the app closes
+    Ban,
+
+    /// Stop them from changing anything, or let them again if it's false, even if they reconnect
+    Mute(bool),
+}
+
+/// The close code sent to kicked clients, so they know not to reconnect
+pub(super) const KICKED_CLOSE_CODE: u16 = 4000;
+
+/// How many wrong PINs an address can enter before it has to wait between tries
+const FREE_PIN_ATTEMPTS: u32 = 5;
+
+/// How long an address waits once it's out of free tries, doubling with every wrong PIN after that
+const PIN_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_PIN_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// How many wrong PINs one connection can enter before it has to reconnect to try again
+pub(super) const MAX_WRONG_PINS_PER_CONNECTION: u32 = 10;
+
+/// The PINs are counted separately, so knowing the table PIN doesn't reset the tries at the GM PIN
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum PinKind {
+    Gm,
+    Table,
+
+    /// Not a PIN, but it can be guessed the same way
+    SyncPassphrase,
+}
+
+impl PinKind {
+    /// What the PIN's called in messages to the player
+    pub fn name(self) -> &'static str {
+        match self {
+            PinKind::Gm => "GM PIN",
+            PinKind::Table => "table PIN",
+            PinKind::SyncPassphrase => "passphrase",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PinAttempts {
+    wrong: u32,
+    locked_until: Option<Instant>,
+}
+
+/// Routes the GM's actions to the tasks serving each connection, and remembers who's banned or muted
+///
+/// It's kept outside of the server like the PINs, so bans and mutes last through restarts
+#[derive(Debug, Default)]
+pub(super) struct Moderation {
+    connections: HashMap<u64, mpsc::UnboundedSender<Action>>,
+    banned_players: HashSet<u32>,
+    banned_addresses: HashSet<IpAddr>,
+
+    /// By player id, so reconnecting doesn't unmute them
+    muted_players: HashSet<u32>,
+
+    /// Wrong PINs by address, so they can't be guessed by trying every one
+    pin_attempts: HashMap<(IpAddr, PinKind), PinAttempts>,
+}
+
+impl Moderation {
+    /// Start taking actions for a connection
+    pub fn register(&mut self, connection: u64) -> mpsc::UnboundedReceiver<Action> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        self.connections.insert(connection, sender);
+
+        receiver
+    }
+
+    pub fn unregister(&mut self, connection: u64) {
+        self.connections.remove(&connection);
+    }
+
+    /// Send an action to the connection's task, does nothing if it's already closed
+    pub fn send(&self, connection: u64, action: Action) {
+        if let Some(sender) = self.connections.get(&connection) {
+            sender.send(action).ok();
+        }
+    }
+
+    pub fn ban(&mut self, player: Option<u32>, address: IpAddr) {
+        self.banned_players.extend(player);
+        self.banned_addresses.insert(address);
+    }
+
+    pub fn is_player_banned(&self, player: u32) -> bool {
+        self.banned_players.contains(&player)
+    }
+
+    pub fn is_address_banned(&self, address: IpAddr) -> bool {
+        self.banned_addresses.contains(&address)
+    }
+
+    pub fn set_muted(&mut self, player: u32, muted: bool) {
+        if muted {
+            self.muted_players.insert(player);
+        } else {
+            self.muted_players.remove(&player);
+        }
+    }
+
+    pub fn is_player_muted(&self, player: u32) -> bool {
+        self.muted_players.contains(&player)
+    }
+
+    /// How long the address has to wait before trying the PIN again, None if it can try now
+    pub fn pin_lockout(&self, address: IpAddr, kind: PinKind) -> Option<Duration> {
+        let locked_until = self.pin_attempts.get(&(address, kind))?.locked_until?;
+
+        locked_until.checked_duration_since(Instant::now())
+    }
+
+    /// Remember whether the address got the PIN right, every wrong one after the free tries makes it wait longer
+    pub fn pin_attempted(&mut self, address: IpAddr, kind: PinKind, correct: bool) {
+        if correct {
+            self.pin_attempts.remove(&(address, kind));
+            return;
+        }
+
+        let attempts = self.pin_attempts.entry((address, kind)).or_default();
+        attempts.wrong += 1;
+
+        if let Some(over) = attempts.wrong.checked_sub(FREE_PIN_ATTEMPTS) {
+            let backoff = PIN_BACKOFF
+                .saturating_mul(2u32.saturating_pow(over))
+                .min(MAX_PIN_BACKOFF);
+
+            attempts.locked_until = Some(Instant::now() + backoff);
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+    select,
+    sync::{oneshot, RwLock},
+    time::sleep,
+};
+use tracing::{error, info};
+
+use crate::utils::unix_time;
+
+use super::{
+    events::{replay_until, EventLog, GameEvent},
+    game::GameState,
+    rooms::Rooms,
+    server::InternalMessage,
+    storage::{self, Storage, StorageKind},
+};
+
+/// Bump this whenever the format of the save file changes, and add a migration from the old version to `migrate`
+const SCHEMA_VERSION: u64 = 2;
+
+/// How long to wait after something changes before saving, so a burst of changes only gets saved once
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// What gets written to the save file
+#[derive(Serialize)]
+struct SaveFile<'a> {
+    version: u64,
+
+    /// The sequence number of the last event every room includes, the events after it are applied on top when it's loaded
+    sequence: u64,
+
+    rooms: HashMap<&'a str, &'a GameState>,
+}
+
+/// What gets read from the save file, after it's been migrated to the current version
+#[derive(Deserialize)]
+struct LoadedSaveFile {
+    sequence: u64,
+
+    rooms: HashMap<String, GameState>,
+}
+
+/// Read a save file, migrating it to the current version
+fn parse_save_file(raw: &[u8]) -> Result<LoadedSaveFile, String> {
+    serde_json::from_slice(raw)
+        .map_err(|e| e.to_string())
+        .and_then(migrate)
+        .and_then(|v| serde_json::from_value::<LoadedSaveFile>(v).map_err(|e| e.to_string()))
+}
+
+/// Load the rooms the way they were when the server last stopped
+///
+/// The last snapshot is loaded, and then every event logged after it is applied on top
+pub(super) fn load(storage: Arc<dyn Storage>) -> Rooms {
+    let (states, sequence) = load_snapshot(&*storage);
+
+    let states = replay_until(&*storage, states, sequence, u64::MAX);
+
+    let last_sequence = states
+        .values()
+        .map(|state| state.last_event)
+        .fold(sequence, u64::max);
+
+    info!("Loaded {} rooms", states.len());
+
+    Rooms::from_states(
+        states,
+        Arc::new(EventLog::open(storage, last_sequence, sequence)),
+    )
+}
+
+/// Load the rooms from the last snapshot, and the sequence number of the last event it includes
+///
+/// If the snapshot can't be read, it's moved out of the way so it doesn't get overwritten, and the rooms get rebuilt from the event log
+fn load_snapshot(storage: &dyn Storage) -> (HashMap<String, GameState>, u64) {
+    let raw = match storage.load_snapshot() {
+        Ok(Some(v)) => v,
+        Ok(None) => return (HashMap::new(), 0),
+        Err(e) => {
+            error!("Couldn't read the saved state: {}", e);
+            return (HashMap::new(), 0);
+        }
+    };
+
+    match parse_save_file(&raw) {
+        Ok(v) => (v.rooms, v.sequence),
+
+        Err(e) => {
+            error!("The saved state is invalid: {}", e);
+
+            if let Err(e) = storage.discard_snapshot() {
+                error!(
+                    "Couldn't move the invalid saved state out of the way: {}",
+                    e
+                );
+            }
+
+            (HashMap::new(), 0)
+        }
+    }
+}
+
+/// Upgrade a save file from an older version of the server to the current format
+fn migrate(mut save: Value) -> Result<Value, String> {
+    let version = save
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or("The save file doesn't have a version")?;
+
+    match version {
+        SCHEMA_VERSION => Ok(save),
+        v if v > SCHEMA_VERSION => Err(format!(
+            "The save file is from a newer version of the server (version {})",
+            v
+        )),
+        // Migrations go here, each one should upgrade the save by one version and call `migrate` again
+
+        // Version 1 was from before the log was rotated, so the whole log gets read on top of it
+        1 => {
+            save["sequence"] = 0.into();
+            save["version"] = 2.into();
+
+            migrate(save)
+        }
+
+        v => Err(format!("There's no migration from save version {}", v)),
+    }
+}
+
+/// Serialize every room, in the same format as the save file so it can be migrated the same way too
+pub(super) async fn backup(rooms: &RwLock<Rooms>) -> serde_json::Result<Vec<u8>> {
+    let sequence = rooms.read().await.event_log().last_sequence();
+
+    serialize(rooms, sequence).await
+}
+
+/// Serialize every room as a save file that includes every event up to `sequence`
+///
+/// The sequence number has to be read before the rooms are, so no room can be missing an event before it
+async fn serialize(rooms: &RwLock<Rooms>, sequence: u64) -> serde_json::Result<Vec<u8>> {
+    let states = rooms.read().await.states();
+
+    let mut guards = Vec::with_capacity(states.len());
+    for (name, state) in &states {
+        guards.push((name.as_str(), state.read().await));
+    }
+
+    let save_file = SaveFile {
+        version: SCHEMA_VERSION,
+        sequence,
+        rooms: guards
+            .iter()
+            .map(|(name, state)| (*name, &**state))
+            .collect(),
+    };
+
+    serde_json::to_vec(&save_file)
+}
+
+/// Rebuild every room as it was at the given time from the event log, in the same format as a backup
+pub fn replay(storage: StorageKind, timestamp: u64) -> serde_json::Result<Vec<u8>> {
+    let storage = storage::open(storage);
+
+    // Start from the newest checkpoint before the time, so only the events after it need to be read
+    let checkpoint = storage
+        .checkpoint_before(timestamp)
+        .and_then(|raw| raw.map(|v| parse_save_file(&v)).transpose());
+
+    let (states, sequence) = match checkpoint {
+        Ok(Some(v)) => (v.rooms, v.sequence),
+        Ok(None) => (HashMap::new(), 0),
+        Err(e) => {
+            error!(
+                "Couldn't read the checkpoint, replaying the whole log: {}",
+                e
+            );
+            (HashMap::new(), 0)
+        }
+    };
+
+    let states = replay_until(&*storage, states, sequence, timestamp);
+
+    let last_sequence = states
+        .values()
+        .map(|state| state.last_event)
+        .fold(sequence, u64::max);
+
+    let save_file = SaveFile {
+        version: SCHEMA_VERSION,
+        sequence: last_sequence,
+        rooms: states
+            .iter()
+            .map(|(name, state)| (name.as_str(), state))
+            .collect(),
+    };
+
+    serde_json::to_vec(&save_file)
+}
+
+/// Replace the rooms in a backup with the backed up versions, rooms that aren't in the backup are left alone
+///
+/// Returns how many rooms were restored
+///
+/// The rooms are saved as a checkpoint straight after, so the log doesn't need to hold the backup to be replayed
+pub(super) async fn restore(rooms_lock: &RwLock<Rooms>, raw: &[u8]) -> Result<usize, String> {
+    let loaded = parse_save_file(raw)?;
+
+    let restored = loaded.rooms.len();
+
+    let mut rooms = rooms_lock.write().await;
+
+    for (campaign, backed_up) in loaded.rooms {
+        let room = rooms.open(&campaign);
+
+        let mut state = room.state.write().await;
+
+        let mut old = std::mem::replace(&mut *state, backed_up);
+
+        // The backup's from somewhere else, but the room still records to the same log
+        state.recorder = old.recorder.take();
+        state.record(GameEvent::Restored);
+
+        // Tell everyone in the room what changed, as if it happened one character at a time
+        for (name, character) in old.characters {
+            let unchanged = state
+                .characters
+                .get(&name)
+                .is_some_and(|v| v.visibility == character.visibility);
+
+            if !unchanged {
+                room.broadcaster
+                    .send(InternalMessage::VisibilityChanged {
+                        name,
+                        owner: character.owner,
+                        previous: character.visibility,
+                    })
+                    .ok();
+            }
+        }
+
+        for name in state.characters.keys() {
+            room.broadcaster
+                .send(InternalMessage::CharacterUpdated {
+                    name: name.clone(),
+                    overwrite: true,
+                })
+                .ok();
+        }
+
+        room.broadcaster
+            .send(InternalMessage::TurnChanged {
+                round: state.initiative.round,
+                character: state.initiative.current_turn().map(str::to_owned),
+            })
+            .ok();
+
+        room.changed.notify();
+    }
+
+    drop(rooms);
+
+    save(rooms_lock, true).await;
+
+    Ok(restored)
+}
+
+/// Store a backup of every room, returning where it went
+pub(super) async fn export_backup(rooms: &RwLock<Rooms>) -> Result<String, String> {
+    let serialized = backup(rooms).await.map_err(|e| e.to_string())?;
+
+    let storage = rooms.read().await.storage();
+
+    tokio::task::spawn_blocking(move || storage.save_backup(unix_time(), &serialized))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// Restore the newest backup, returning where it came from
+pub(super) async fn import_latest_backup(rooms: &RwLock<Rooms>) -> Result<String, String> {
+    let storage = rooms.read().await.storage();
+
+    let (newest, raw) = tokio::task::spawn_blocking(move || storage.latest_backup())
+        .await
+        .map_err(|e| e.to_string())??
+        .ok_or("There aren't any backups")?;
+
+    restore(rooms, &raw).await?;
+
+    Ok(newest)
+}
+
+/// Write every room to the storage as a snapshot, keeping it as a checkpoint too if it's been a while since the last one or `checkpoint` is set
+async fn save(rooms: &RwLock<Rooms>, checkpoint: bool) {
+    let (sequence, checkpoint) = rooms.read().await.event_log().mark_snapshot(checkpoint);
+
+    let serialized = match serialize(rooms, sequence).await {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Couldn't serialize the state: {}", e);
+            return;
+        }
+    };
+
+    let storage = rooms.read().await.storage();
+
+    let result = tokio::task::spawn_blocking(move || {
+        storage.save_snapshot(&serialized)?;
+
+        if checkpoint {
+            storage.save_checkpoint(unix_time(), &serialized)?;
+        }
+
+        Ok::<_, String>(())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Couldn't save the state: {}", e),
+        Err(e) => error!("Couldn't save the state: {}", e),
+    }
+}
+
+/// Save the rooms a little while after they change, until told to stop, and then save one last time
+pub(super) async fn save_when_changed(rooms: Arc<RwLock<Rooms>>, mut stop: oneshot::Receiver<()>) {
+    let changed = rooms.read().await.changed();
+
+    loop {
+        select! {
+            _ = changed.saver.notified() => {}
+            _ = &mut stop => break,
+        }
+
+        select! {
+            _ = sleep(SAVE_DELAY) => {}
+            _ = &mut stop => break,
+        }
+
+        save(&rooms, false).await;
+    }
+
+    save(&rooms, false).await;
+
+    // The snapshot has every event, but `replay` needs them in the log too
+    rooms.read().await.event_log().flush().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn current_saves_are_left_alone() {
+        let save = json!({ "version": SCHEMA_VERSION, "sequence": 4, "rooms": {} });
+
+        assert_eq!(migrate(save.clone()), Ok(save));
+    }
+
+    #[test]
+    fn version_1_saves_read_the_whole_log() {
+        let save = json!({ "version": 1, "rooms": { "Campaign": {} } }).to_string();
+
+        let loaded = parse_save_file(save.as_bytes()).unwrap();
+
+        assert_eq!(loaded.sequence, 0);
+        assert!(loaded.rooms.contains_key("Campaign"));
+    }
+
+    #[test]
+    fn current_saves_need_a_sequence() {
+        let save = json!({ "version": SCHEMA_VERSION, "rooms": {} }).to_string();
+
+        assert!(parse_save_file(save.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn saves_need_a_version() {
+        assert!(migrate(json!({ "rooms": {} })).is_err());
+        assert!(migrate(json!({ "version": "1", "rooms": {} })).is_err());
+    }
+
+    #[test]
+    fn saves_from_newer_servers_are_refused() {
+        let error = migrate(json!({ "version": SCHEMA_VERSION + 1, "rooms": {} })).unwrap_err();
+
+        assert!(error.contains("newer version"));
+    }
+}
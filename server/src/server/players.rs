@@ -0,0 +1,73 @@
+use std::{collections::HashMap, sync::Arc};
+
+use tracing::{error, info};
+
+use super::storage::Storage;
+
+/// The secret token each player proves who they are with when they reconnect
+///
+/// Player ids are sent to everyone in a room, so an id on its own isn't enough to take over someone's characters
+#[derive(Debug)]
+pub(super) struct Players {
+    tokens: HashMap<u32, String>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Players {
+    /// Load the tokens of everyone who's played before, so they can reconnect after the server restarts
+    pub fn load(storage: Arc<dyn Storage>) -> Players {
+        let tokens = match storage.load_tokens() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Couldn't read the players' tokens: {}", e);
+                HashMap::new()
+            }
+        };
+
+        info!("Loaded {} players", tokens.len());
+
+        Players { tokens, storage }
+    }
+
+    /// Make up an id & token for a new player
+    pub fn issue(&mut self) -> (u32, String) {
+        let id = loop {
+            let id = rand::random();
+
+            if !self.tokens.contains_key(&id) {
+                break id;
+            }
+        };
+
+        let token = format!("{:032x}", rand::random::<u128>());
+
+        self.tokens.insert(id, token.clone());
+
+        let storage = Arc::clone(&self.storage);
+        let saved = token.clone();
+
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = storage.save_token(id, &saved) {
+                error!("Couldn't save player {}'s token: {}", id, e);
+            }
+        });
+
+        (id, token)
+    }
+
+    /// Whether the token is the one the player was given
+    pub fn verify(&self, id: u32, token: &str) -> bool {
+        match self.tokens.get(&id) {
+            // Every byte is compared so how long it takes doesn't give away how much of the token was right
+            Some(expected) => {
+                expected.len() == token.len()
+                    && expected
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |acc, (a, b)| acc | (a ^ b))
+                        == 0
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+use super::config::RateLimits;
+
+/// Keeps a connection from sending more messages than the rate limits allow
+///
+/// Each message uses up a token, and tokens come back at `messages_per_second` up to `burst`
+pub(super) struct RateLimiter {
+    limits: RateLimits,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            tokens: limits.burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Use up a token for a message, returns false if there aren't any left and the message should be turned away
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+
+        self.tokens = (self.tokens + elapsed * self.limits.messages_per_second as f64)
+            .min(self.limits.burst as f64);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use tokio::{
+    sync::{broadcast, Notify, RwLock},
+    time::sleep,
+};
+
+use super::{
+    config::{Config, GracePeriods},
+    events::{EventLog, EventRecorder, GameEvent},
+    game::GameState,
+    players::Players,
+    server::InternalMessage,
+    storage::Storage,
+};
+
+/// A campaign being played on the server, everything that happens in a room is only seen by the people in it
+pub(super) struct Room {
+    pub state: Arc<RwLock<GameState>>,
+    pub broadcaster: broadcast::Sender<InternalMessage>,
+
+    /// How many connections each player has in the room
+    players: HashMap<u32, usize>,
+
+    /// Counts up every time a player leaves, so someone who left can be told apart from someone who left, came back, and left again
+    departures: HashMap<u32, u64>,
+}
+
+/// A handle to a room that a session is in
+#[derive(Clone)]
+pub(super) struct JoinedRoom {
+    pub name: String,
+    pub state: Arc<RwLock<GameState>>,
+    pub broadcaster: broadcast::Sender<InternalMessage>,
+
+    /// Tells the server that the room's state changed and needs to be saved
+    pub changed: Arc<Changes>,
+}
+
+/// Tells everything that keeps up with the rooms that one of them changed
+///
+/// Each gets its own `Notify`, since one that's notified while nobody's waiting only remembers it for a single waiter
+#[derive(Debug, Default)]
+pub(super) struct Changes {
+    pub saver: Notify,
+    pub dashboard: Notify,
+}
+
+impl Changes {
+    pub fn notify(&self) {
+        self.saver.notify_one();
+        self.dashboard.notify_one();
+    }
+}
+
+/// Every room on the server, by campaign name
+///
+/// Rooms stick around when everyone leaves so the GM can prepare a campaign before anyone joins
+pub(super) struct Rooms {
+    rooms: HashMap<String, Room>,
+
+    /// Notified whenever any room changes
+    changed: Arc<Changes>,
+
+    events: Arc<EventLog>,
+
+    /// Every player's secret token, kept with the rooms since they're stored in the same place
+    players: Players,
+
+    /// How long a player's characters stay in a room after they leave, so they can reconnect without losing them
+    grace_period: Duration,
+}
+
+impl Room {
+    fn new(name: &str, mut state: GameState, events: &Arc<EventLog>) -> Room {
+        let (broadcaster, _) = broadcast::channel(32);
+
+        state.recorder = Some(EventRecorder {
+            room: name.to_owned(),
+            log: Arc::clone(events),
+        });
+
+        Room {
+            state: Arc::new(RwLock::new(state)),
+            broadcaster,
+            players: HashMap::new(),
+            departures: HashMap::new(),
+        }
+    }
+}
+
+impl Rooms {
+    /// Make rooms for games that were saved before, recording what happens in them to the event log
+    pub fn from_states(states: HashMap<String, GameState>, events: Arc<EventLog>) -> Rooms {
+        Rooms {
+            rooms: states
+                .into_iter()
+                .map(|(name, state)| {
+                    let room = Room::new(&name, state, &events);
+                    (name, room)
+                })
+                .collect(),
+            changed: Arc::new(Changes::default()),
+            players: Players::load(Arc::clone(events.storage())),
+            events,
+            grace_period: GracePeriods::default().reconnect(),
+        }
+    }
+
+    /// Apply the config's settings for rooms, opening the rooms that should be ready from the start
+    pub fn configure(&mut self, config: &Config) {
+        self.grace_period = config.grace_periods.reconnect();
+
+        for campaign in &config.rooms.open_on_start {
+            self.open(campaign);
+        }
+    }
+
+    /// Join the room for a campaign, making it if it doesn't exist yet
+    pub fn join(&mut self, campaign: &str, id: u32) -> JoinedRoom {
+        let room = self.open(campaign);
+
+        *self
+            .rooms
+            .get_mut(campaign)
+            .unwrap()
+            .players
+            .entry(id)
+            .or_insert(0) += 1;
+
+        room
+    }
+
+    /// Get a handle to the room for a campaign without joining it, making it if it doesn't exist yet
+    pub fn open(&mut self, campaign: &str) -> JoinedRoom {
+        if !self.rooms.contains_key(campaign) {
+            let room = Room::new(campaign, GameState::default(), &self.events);
+            self.rooms.insert(campaign.to_owned(), room);
+            self.changed.notify();
+        }
+
+        let room = &self.rooms[campaign];
+
+        JoinedRoom {
+            name: campaign.to_owned(),
+            state: Arc::clone(&room.state),
+            broadcaster: room.broadcaster.clone(),
+            changed: Arc::clone(&self.changed),
+        }
+    }
+
+    /// Leave a room, returning which time this is that the player left it
+    pub fn leave(&mut self, campaign: &str, id: u32) -> u64 {
+        let room = match self.rooms.get_mut(campaign) {
+            Some(v) => v,
+            None => return 0,
+        };
+
+        if let Some(connections) = room.players.get_mut(&id) {
+            *connections -= 1;
+
+            if *connections == 0 {
+                room.players.remove(&id);
+            }
+        }
+
+        let departure = room.departures.entry(id).or_insert(0);
+        *departure += 1;
+
+        *departure
+    }
+
+    /// Whether the player came back to the room since they left it for the given time
+    fn came_back(&self, campaign: &str, id: u32, departure: u64) -> bool {
+        match self.rooms.get(campaign) {
+            Some(room) => {
+                room.players.contains_key(&id) || room.departures.get(&id) != Some(&departure)
+            }
+            None => false,
+        }
+    }
+
+    /// The name of every room and how many people are in it, sorted by name
+    pub fn summary(&self) -> Vec<(String, usize)> {
+        let mut summary = self
+            .rooms
+            .iter()
+            .map(|(name, room)| (name.clone(), room.players.values().sum()))
+            .collect::<Vec<_>>();
+
+        summary.sort();
+
+        summary
+    }
+
+    /// The state of every room, by campaign name
+    pub fn states(&self) -> Vec<(String, Arc<RwLock<GameState>>)> {
+        self.rooms
+            .iter()
+            .map(|(name, room)| (name.clone(), Arc::clone(&room.state)))
+            .collect()
+    }
+
+    /// Notified whenever any room changes
+    pub fn changed(&self) -> Arc<Changes> {
+        Arc::clone(&self.changed)
+    }
+
+    /// Make up an id & secret token for a new player
+    pub fn issue_player(&mut self) -> (u32, String) {
+        self.players.issue()
+    }
+
+    /// Whether the token is the one the player was given
+    pub fn verify_player(&self, id: u32, token: &str) -> bool {
+        self.players.verify(id, token)
+    }
+
+    /// Where every room's events are recorded
+    pub fn event_log(&self) -> Arc<EventLog> {
+        Arc::clone(&self.events)
+    }
+
+    /// Where the rooms are kept between runs
+    pub fn storage(&self) -> Arc<dyn Storage> {
+        Arc::clone(self.events.storage())
+    }
+}
+
+/// Take a player's characters out of a room they left, unless they come back within the grace period
+///
+/// Only for players, the GM owns the room's NPCs which have to outlive the GM's connection
+pub(super) fn remove_characters_later(
+    rooms: Arc<RwLock<Rooms>>,
+    room: JoinedRoom,
+    id: u32,
+    departure: u64,
+) {
+    tokio::spawn(async move {
+        let grace_period = rooms.read().await.grace_period;
+        sleep(grace_period).await;
+
+        if rooms.read().await.came_back(&room.name, id, departure) {
+            return;
+        }
+
+        let mut state = room.state.write().await;
+
+        let owned = state
+            .characters
+            .iter()
+            .filter(|(_, character)| character.owner == id)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+
+        if owned.is_empty() {
+            return;
+        }
+
+        for name in owned {
+            let removed = match state.remove_character(&name) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            state.record(GameEvent::CharacterRemoved { name: name.clone() });
+
+            // The character's gone, so everyone who could see it gets told it was removed
+            room.broadcaster
+                .send(InternalMessage::VisibilityChanged {
+                    name,
+                    owner: removed.owner,
+                    previous: removed.visibility,
+                })
+                .ok();
+        }
+
+        room.changed.notify();
+    });
+}
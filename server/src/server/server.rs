@@ -1,183 +1,765 @@
-use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use hyper::{service, Body, Request, Response, Server};
-use hyper_tungstenite::{tungstenite::Error, HyperWebsocket};
-use iced::futures::StreamExt;
+use futures::{future, FutureExt, SinkExt, StreamExt};
+use hyper::{
+    server::conn::{AddrIncoming, AddrStream},
+    service,
+    upgrade::Upgraded,
+    Body, Request, Response, Server, StatusCode,
+};
+use hyper_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error, Message,
+    },
+    HyperWebsocket, WebSocketStream,
+};
 use tokio::{
     runtime::Runtime,
     sync::{
         broadcast,
         mpsc::{self, UnboundedSender},
-        oneshot, RwLock,
+        oneshot, watch, RwLock,
     },
+    time,
 };
+use tracing::{debug, error, field, info, info_span, trace, warn, Instrument};
 
 use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
-    server::websocket::{on_message, received_internal_message},
-    utils::get_local_ip,
+    server::websocket::{
+        on_message, received_internal_message, report_connection, send, send_state,
+    },
+    utils::{await_option, get_local_ip},
 };
 
 use super::{
+    api::{handle_api, respond},
+    area_effect::{Ability, AreaEffectTarget},
+    conditions::Condition,
+    config::{Port, RateLimits, DEFAULT_PORT},
+    damage::DamageLogEntry,
+    error::ServerError,
+    events::GameEvent,
+    history::CharacterVersion,
+    instance::{remove_lock, write_lock},
+    moderation::{Action, Moderation, KICKED_CLOSE_CODE},
+    rate_limit::RateLimiter,
+    rooms::{remove_characters_later, Rooms},
+    session::{Pins, Session},
+    templates::NpcTemplate,
+    visibility::Visibility,
+    Role,
     ServerMessage::{self, *},
-    ServerStatus::*,
+    ServerStatus::{self, *},
 };
 
 #[derive(Debug, Clone)]
 pub(super) enum InternalMessage {
     CharacterUpdated {
-        character_data: String,
-        player_id: u32,
+        name: String,
+
+        /// Whether the server changed the character, meaning the owner needs to update their copy too
+        overwrite: bool,
+    },
+    VisibilityChanged {
+        name: String,
+
+        /// Who owned the character & who could see it before, so only sessions that knew about it are told it's gone
+        owner: u32,
+        previous: Visibility,
+    },
+    TurnChanged {
+        round: u32,
+        character: Option<String>,
+    },
+    ConditionsUpdated {
+        character: String,
+        conditions: Vec<Condition>,
+    },
+    ConditionExpired {
+        character: String,
+        condition: Condition,
+    },
+    DamageApplied {
+        entry: DamageLogEntry,
     },
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn start_server(
-    port: u16,
+    addresses: Vec<IpAddr>,
+    port: Port,
+    rate_limits: RateLimits,
     cancel_signal: oneshot::Receiver<()>,
     signal_sender: mpsc::UnboundedSender<ServerMessage>,
+    pins: watch::Receiver<Pins>,
+    rooms: Arc<RwLock<Rooms>>,
+    moderation: Arc<Mutex<Moderation>>,
     runtime: Arc<Runtime>,
 ) {
-    println!("Starting server");
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    info!("Starting server on {:?} port {}", addresses, port);
 
     let cloned_signal_sender = signal_sender.clone();
 
-    let (internal_message_broadcaster, _) = broadcast::channel(32);
-
-    let character_states = Arc::new(RwLock::new(HashMap::new()));
-
     // Register the request handler
-    let make_service = service::make_service_fn(move |_conn| {
+    let make_service = service::make_service_fn(move |conn: &AddrStream| {
+        // Listening on `::` gives IPv4 clients addresses like ::ffff:192.168.1.2, which are easier to read as plain IPv4
+        let remote_address = SocketAddr::new(
+            conn.remote_addr().ip().to_canonical(),
+            conn.remote_addr().port(),
+        );
         let runtime = Arc::clone(&runtime);
         let signal_sender = signal_sender.clone();
-        let internal_message_broadcaster = internal_message_broadcaster.clone();
-        let character_states = Arc::clone(&character_states);
+        let rooms = Arc::clone(&rooms);
+        let moderation = Arc::clone(&moderation);
+        let pins = pins.clone();
 
         let service = service::service_fn(move |req| {
             handle_request(
                 req,
+                remote_address,
                 Arc::clone(&runtime),
                 signal_sender.clone(),
-                internal_message_broadcaster.clone(),
-                Arc::clone(&character_states),
+                Arc::clone(&rooms),
+                Arc::clone(&moderation),
+                pins.clone(),
+                rate_limits,
             )
         });
 
         async move { Ok::<_, Infallible>(service) }
     });
 
-    let server = Server::bind(&addr).serve(make_service);
+    let (listeners, port) = match bind(&addresses, port) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Couldn't start the server: {}", e);
+            cloned_signal_sender
+                .send(Status(ServerStatus::Error(e)))
+                .ok();
+            return;
+        }
+    };
+
+    let addresses = listeners
+        .iter()
+        .map(|(_, addr)| addr.ip())
+        .collect::<Vec<_>>();
 
-    // Configure the server to stop when the oneshot value is received
-    let graceful = server.with_graceful_shutdown(async {
-        cancel_signal.await.ok();
-    });
+    // Configure every listener to stop when the oneshot value is received
+    let cancel_signal = cancel_signal.shared();
+
+    let graceful = future::try_join_all(listeners.into_iter().map(|(listener, addr)| {
+        info!("Listening on {}", addr);
+
+        let cancel_signal = cancel_signal.clone();
+
+        listener
+            .serve(make_service.clone())
+            .with_graceful_shutdown(async {
+                cancel_signal.await.ok();
+            })
+    }));
+
+    let lock = write_lock(SocketAddr::from((addresses[0], port)));
 
     match get_local_ip() {
-        Some(ip) => cloned_signal_sender.send(Status(Online { ip })),
-        None => cloned_signal_sender.send(Status(OnlineNoIp)),
+        Some(ip) => cloned_signal_sender.send(Status(Online {
+            ip,
+            port,
+            addresses,
+        })),
+        None => cloned_signal_sender.send(Status(OnlineNoIp { port, addresses })),
     }
     .ok();
 
     // Run forever, until there's an error or the server shuts down
-    match graceful.await {
+    let result = graceful.await;
+
+    remove_lock(&lock);
+
+    match result {
         Ok(_) => {
-            println!("Server stopped");
+            info!("Server stopped");
             cloned_signal_sender.send(Status(Offline)).ok();
         }
 
         Err(e) => {
-            eprintln!("Server errored: {}", e);
-            cloned_signal_sender.send(Status(Error)).ok();
+            error!("Server errored: {}", e);
+            cloned_signal_sender
+                .send(Status(ServerStatus::Error(ServerError::Other {
+                    message: e.to_string(),
+                })))
+                .ok();
+        }
+    }
+}
+
+/// Servers ready to accept connections, along with where they're listening
+type Listeners = Vec<(hyper::server::Builder<AddrIncoming>, SocketAddr)>;
+
+/// Start listening for connections on every address, returning the listeners and the port they're all listening on
+fn bind(addresses: &[IpAddr], port: Port) -> Result<(Listeners, u16), ServerError> {
+    if addresses.is_empty() {
+        return Err(ServerError::Other {
+            message: "There aren't any addresses to listen on".to_owned(),
+        });
+    }
+
+    match port {
+        Port::Fixed(port) => bind_port(addresses, port),
+
+        // The default port is tried first so the address players use stays the same when it can, and port 0 gets any free port from the OS
+        Port::Auto => bind_port(addresses, DEFAULT_PORT).or_else(|_| bind_port(addresses, 0)),
+    }
+}
+
+/// Listen on the same port on every address, if the port is 0 the OS picks one for the first address and the rest use that
+fn bind_port(addresses: &[IpAddr], mut port: u16) -> Result<(Listeners, u16), ServerError> {
+    let every_ipv4 = addresses.contains(&IpAddr::from([0, 0, 0, 0]));
+    let every_ipv6 = addresses.contains(&IpAddr::from([0u16; 8]));
+
+    // Listening on `::` takes IPv4 connections too, unless they're being listened for separately on 0.0.0.0
+    let dual_stack = every_ipv6 && !every_ipv4;
+
+    let mut listeners = Vec::new();
+
+    for &address in addresses {
+        // The OS won't listen on an address that's already covered by listening on every address
+        let covered = !address.is_unspecified()
+            && match address {
+                IpAddr::V4(_) => every_ipv4 || dual_stack,
+                IpAddr::V6(_) => every_ipv6,
+            };
+
+        if covered {
+            debug!(
+                "Not listening on {} separately, it's already covered",
+                address
+            );
+            continue;
         }
+
+        let dual_stack = dual_stack && address.is_unspecified() && address.is_ipv6();
+
+        let addr = SocketAddr::from((address, port));
+
+        let listener = match listen(addr, dual_stack) {
+            Ok(v) => v,
+
+            // Some computers have IPv6 turned off, so fall back to every IPv4 address
+            Err(e)
+                if dual_stack
+                    && !matches!(e.kind(), ErrorKind::AddrInUse | ErrorKind::PermissionDenied) =>
+            {
+                warn!("Couldn't listen on IPv6, only listening on IPv4: {}", e);
+
+                let addr = SocketAddr::from(([0, 0, 0, 0], port));
+                listen(addr, false).map_err(|e| ServerError::from_bind(&e, addr))?
+            }
+
+            Err(e) => return Err(ServerError::from_bind(&e, addr)),
+        };
+
+        let addr = listener.local_addr().map_err(|e| ServerError::Other {
+            message: e.to_string(),
+        })?;
+
+        port = addr.port();
+
+        let server = Server::from_tcp(listener).map_err(|e| ServerError::Other {
+            message: e.to_string(),
+        })?;
+
+        listeners.push((server, addr));
     }
+
+    Ok((listeners, port))
+}
+
+/// Start listening on one address, which only takes IPv4 connections on an IPv6 address if it's dual stack
+fn listen(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+
+    // Lets the server start again straight after stopping, like std's TcpListener does
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+
+    Ok(socket.into())
 }
 
+/// What banned players are told when they try to get back in
+const BANNED_MESSAGE: &str = "The GM banned you from the game";
+
+/// How often connections are pinged to measure their latency
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The id the next websocket connection gets in the logs
+static NEXT_CONNECTION: AtomicU64 = AtomicU64::new(1);
+
 /// Handle requests sent to the server
+#[allow(clippy::too_many_arguments)]
 async fn handle_request(
     request: Request<Body>,
+    remote_address: SocketAddr,
     runtime: Arc<Runtime>,
     signal_sender: UnboundedSender<ServerMessage>,
-    internal_message_broadcaster: broadcast::Sender<InternalMessage>,
-    character_states: Arc<RwLock<HashMap<String, (u32, String)>>>,
+    rooms: Arc<RwLock<Rooms>>,
+    moderation: Arc<Mutex<Moderation>>,
+    pins: watch::Receiver<Pins>,
+    rate_limits: RateLimits,
 ) -> Result<Response<Body>, Error> {
+    // Banned addresses can't do anything, not even use the API
+    if moderation
+        .lock()
+        .unwrap()
+        .is_address_banned(remote_address.ip())
+    {
+        return Ok(respond(StatusCode::FORBIDDEN, BANNED_MESSAGE));
+    }
+
     if hyper_tungstenite::is_upgrade_request(&request) {
-        println!("Received upgrade request");
+        debug!("Received upgrade request");
         let (response, websocket) = hyper_tungstenite::upgrade(request, None)?;
 
+        // Every connection gets its own id, so everything logged for it can be told apart from other connections
+        let connection = NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed);
+        let span = info_span!("connection", id = connection, player = field::Empty);
+
         // Spawn a task to handle the websocket connection.
-        runtime.spawn(async move {
-            if let Err(e) = serve_websocket(
-                websocket,
-                signal_sender.clone(),
-                internal_message_broadcaster.clone(),
-                Arc::clone(&character_states),
-            )
-            .await
-            {
-                eprintln!("Error in websocket connection: {}", e);
+        runtime.spawn(
+            async move {
+                if let Err(e) = serve_websocket(
+                    websocket,
+                    connection,
+                    remote_address,
+                    signal_sender.clone(),
+                    rooms,
+                    moderation,
+                    pins,
+                    rate_limits,
+                )
+                .await
+                {
+                    warn!("Error in websocket connection: {}", e);
+                }
             }
-        });
+            .instrument(span),
+        );
 
         return Ok(response);
     }
 
+    if request.uri().path().starts_with("/api/") {
+        return Ok(handle_api(request, remote_address, rooms, pins, &moderation).await);
+    }
+
     Ok(Response::new(Body::from("Hello HTTP!")))
 }
 
 #[derive(Debug, Deserialize)]
 pub(super) enum FromClientMessage {
-    RequestId {},
-    Id { id: u32 },
-    CharacterUpdated { data: String },
+    RequestId {
+        /// The table PIN, if the GM set one
+        pin: Option<u32>,
+
+        /// The name shown in the server's GUI
+        name: Option<String>,
+    },
+    Id {
+        id: u32,
+
+        /// The secret token the server sent with the id, so nobody else can use it
+        #[serde(default)]
+        token: String,
+
+        pin: Option<u32>,
+        name: Option<String>,
+    },
+    ClaimGm {
+        pin: u32,
+    },
+    JoinRoom {
+        campaign: String,
+    },
+
+    /// Join without being able to own characters or change anything, instead of `RequestId` or `Id`
+    Spectate {
+        pin: Option<u32>,
+        name: Option<String>,
+    },
+    CharacterUpdated {
+        data: String,
+
+        /// The visibility the character starts with if the server hasn't seen it before
+        visibility: Option<Visibility>,
+    },
+    SetVisibility {
+        character: String,
+        visibility: Visibility,
+    },
+    Reveal {
+        character: String,
+    },
+    StartCombat {},
+    NextTurn {},
+    EndCombat {},
+    AddCondition {
+        character: String,
+        condition: Condition,
+    },
+    RemoveCondition {
+        character: String,
+        condition: String,
+    },
+    ApplyDamage {
+        targets: Vec<String>,
+        amount: u32,
+        damage_type: Option<String>,
+    },
+    ApplyHealing {
+        targets: Vec<String>,
+        amount: u32,
+    },
+    AreaEffect {
+        damage: String,
+        damage_type: Option<String>,
+        save: Ability,
+        dc: i64,
+        targets: Vec<String>,
+    },
+    SaveTemplate {
+        template: NpcTemplate,
+    },
+    DeleteTemplate {
+        name: String,
+    },
+    ListTemplates {},
+    SpawnFromTemplate {
+        template: String,
+        count: u32,
+
+        /// Whether to add the NPCs to combat if it's already going on
+        #[serde(default)]
+        add_to_initiative: bool,
+
+        /// Who can see the NPCs, they're hidden unless specified otherwise
+        visibility: Option<Visibility>,
+    },
+    UploadCampaigns {
+        /// The name the player syncs their campaigns under
+        identity: String,
+        passphrase: String,
+
+        /// The campaigns from the browser's local storage, encoded as JSON
+        campaigns: String,
+
+        /// When the campaigns were last changed, in milliseconds since the unix epoch
+        modified: u64,
+
+        /// The modified time of the server's copy the last time this browser synced
+        last_synced: Option<u64>,
+
+        /// Overwrite the server's copy even if it changed since this browser last synced
+        #[serde(default)]
+        force: bool,
+    },
+    DownloadCampaigns {
+        identity: String,
+        passphrase: String,
+    },
+    GetHistory {
+        character: String,
+    },
+
+    /// Put a character back the way it was in an old version
+    UndoTo {
+        character: String,
+        version: u32,
+    },
+}
+
+/// A character as it's sent in `ToClientMessage::Characters`
+#[derive(Debug, Serialize)]
+pub(super) struct SentCharacter {
+    pub data: String,
+    pub player_id: u32,
 }
 
 #[derive(Debug, Serialize)]
 pub(super) enum ToClientMessage {
-    Id { id: u32 },
-    CharacterUpdated { data: String, player_id: u32 },
+    Id {
+        id: u32,
+
+        /// Sent back with the id when reconnecting, to prove it's the same player
+        token: String,
+    },
+    CharacterUpdated {
+        data: String,
+        player_id: u32,
+        overwrite: bool,
+    },
+    CharacterRemoved {
+        name: String,
+    },
+
+    /// Every character the session can see, the client forgets any others it has
+    Characters {
+        characters: Vec<SentCharacter>,
+
+        /// Whether the owners need to update their copies too, like in `CharacterUpdated`
+        overwrite: bool,
+    },
+    TurnChanged {
+        round: u32,
+        character: Option<String>,
+    },
+    ConditionsUpdated {
+        character: String,
+        conditions: Vec<Condition>,
+    },
+    ConditionExpired {
+        character: String,
+        condition: Condition,
+    },
+    DamageApplied {
+        entry: DamageLogEntry,
+    },
+    AreaEffectResolved {
+        damage: i64,
+        targets: Vec<AreaEffectTarget>,
+    },
+    Templates {
+        templates: Vec<NpcTemplate>,
+    },
+    Role {
+        role: Role,
+    },
+    CampaignsUploaded {
+        modified: u64,
+    },
+    CampaignsDownloaded {
+        campaigns: String,
+        modified: u64,
+    },
+
+    /// The campaigns changed on the server and in the browser since they were last synced
+    SyncConflict {
+        server_modified: u64,
+    },
+
+    /// A character's recent versions, newest first
+    History {
+        character: String,
+        versions: Vec<CharacterVersion>,
+    },
+    Error {
+        message: String,
+    },
 }
 
 /// Manage a websocket connection
+#[allow(clippy::too_many_arguments)]
 async fn serve_websocket(
     websocket: HyperWebsocket,
+    connection: u64,
+    remote_address: SocketAddr,
     signal_sender: UnboundedSender<ServerMessage>,
-    internal_message_broadcaster: broadcast::Sender<InternalMessage>,
-    character_states: Arc<RwLock<HashMap<String, (u32, String)>>>,
+    rooms: Arc<RwLock<Rooms>>,
+    moderation: Arc<Mutex<Moderation>>,
+    pins: watch::Receiver<Pins>,
+    rate_limits: RateLimits,
 ) -> Result<(), Error> {
-    let mut internal_message_receiver = internal_message_broadcaster.subscribe();
+    // None until the client joins a room
+    let mut internal_message_receiver: Option<broadcast::Receiver<InternalMessage>> = None;
 
     let mut websocket = websocket.await?;
 
-    let mut id: Option<u32> = None;
+    let mut session = Session::new(connection, remote_address);
+
+    let mut rate_limiter = RateLimiter::new(rate_limits);
+
+    // What the GM does to the connection from the GUI
+    let mut actions = moderation.lock().unwrap().register(connection);
 
-    loop {
+    // Pings measure the connection's latency, and keep the roster in the GUI up to date
+    let mut ping_timer = time::interval(PING_INTERVAL);
+    let mut ping_sent: Option<Instant> = None;
+
+    let result = loop {
         tokio::select! {
             maybe_message = websocket.next() => {
                 // Exit the loop if websocket.next returns none, because if it does, the websocket was closed
-                let message = if let Some(v) = maybe_message { v } else { break };
+                let message = if let Some(v) = maybe_message { v } else { break Ok(()) };
+
+                // Pongs come from the browser rather than the player, so they don't count towards the rate limit
+                if let Ok(Message::Pong(_)) = message {
+                    if let Some(sent) = ping_sent.take() {
+                        session.latency = Some(sent.elapsed());
+                        report_connection(&session, &signal_sender).await;
+                    }
+
+                    continue;
+                }
+
+                if !rate_limiter.allow() {
+                    if let Err(e) = send(&mut websocket, ToClientMessage::Error { message: "You're sending messages too quickly, slow down a bit".to_owned() }).await {
+                        break Err(e);
+                    }
+
+                    continue;
+                }
+
+                if let Err(e) = on_message(message, &mut websocket, &mut session, &mut internal_message_receiver, &signal_sender, &rooms, &pins, &moderation).await {
+                    break Err(e);
+                }
+
+                // Banned players are only found out once they say who they are
+                if session.banned {
+                    break close_kicked(&mut websocket, BANNED_MESSAGE).await;
+                }
+            }
 
-                on_message(message, &mut websocket, &mut id, &signal_sender, &internal_message_broadcaster, &character_states).await?;
+            Some(action) = actions.recv() => match action {
+                Action::Kick => {
+                    info!("Kicked by the GM");
+                    break close_kicked(&mut websocket, "The GM removed you from the game").await;
+                }
+
+                Action::Ban => {
+                    info!("Banned by the GM");
+                    moderation.lock().unwrap().ban(session.id, session.address.ip());
+                    break close_kicked(&mut websocket, BANNED_MESSAGE).await;
+                }
+
+                Action::Mute(muted) => {
+                    if let Some(id) = session.id {
+                        moderation.lock().unwrap().set_muted(id, muted);
+                    }
+
+                    session.muted = muted;
+                    report_connection(&session, &signal_sender).await;
+
+                    if muted {
+                        if let Err(e) = send(&mut websocket, ToClientMessage::Error { message: "The GM muted you, so you can't change anything".to_owned() }).await {
+                            break Err(e);
+                        }
+                    }
+                }
+            },
+
+            _ = ping_timer.tick() => {
+                ping_sent = Some(Instant::now());
+
+                if let Err(e) = websocket.send(Message::Ping(Vec::new())).await {
+                    break Err(e);
+                }
             }
 
-            maybe_internal_message = internal_message_receiver.recv() => {
-                println!("Character updated transmitted internally: {:?}", maybe_internal_message);
+            Some(maybe_internal_message) = await_option(internal_message_receiver.as_mut().map(|v| v.recv())) => {
+                trace!("Character updated transmitted internally: {:?}", maybe_internal_message);
                 let internal_message = match maybe_internal_message {
                     Ok(v) => v,
-                    Err(_) => continue,
+
+                    // Bulk changes like spawning a pack of NPCs can send more than the channel holds, so send the whole room again to catch up, which replaces the client's characters
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("Missed {} changes, sending the whole room again", missed);
+
+                        if let Some(room) = &session.room {
+                            if let Err(e) = send_state(&mut websocket, &session, &room.state, true).await {
+                                break Err(e);
+                            }
+                        }
+
+                        continue;
+                    }
+
+                    Err(broadcast::error::RecvError::Closed) => continue,
                 };
 
-                received_internal_message(internal_message, &mut websocket).await?;
+                if let Err(e) = received_internal_message(internal_message, &mut websocket, &session).await {
+                    break Err(e);
+                }
             }
         }
+    };
+
+    let is_gm = session.is_gm();
+
+    if let (Some(id), Some(room)) = (session.id, session.room) {
+        let mut rooms_lock = rooms.write().await;
+
+        room.state
+            .write()
+            .await
+            .record(GameEvent::PlayerLeft { id });
+
+        // The player's characters stick around for a bit in case they're just reconnecting, the GM's NPCs are kept
+        let departure = rooms_lock.leave(&room.name, id);
+
+        if !is_gm {
+            remove_characters_later(Arc::clone(&rooms), room, id, departure);
+        }
+
+        signal_sender
+            .send(RoomsChanged {
+                rooms: rooms_lock.summary(),
+            })
+            .ok();
     }
 
-    if let Some(id) = id {
-        signal_sender.send(ClosedConnection { id }).ok();
+    moderation.lock().unwrap().unregister(connection);
+
+    if session.id.is_some() {
+        signal_sender
+            .send(ClosedConnection {
+                id: session.connection,
+            })
+            .ok();
     }
 
-    Ok(())
+    result
+}
+
+/// Tell the client why they're being removed, then close the connection in a way that tells them not to reconnect
+async fn close_kicked(
+    websocket: &mut WebSocketStream<Upgraded>,
+    reason: &str,
+) -> Result<(), Error> {
+    send(
+        websocket,
+        ToClientMessage::Error {
+            message: reason.to_owned(),
+        },
+    )
+    .await?;
+
+    websocket
+        .close(Some(CloseFrame {
+            code: CloseCode::from(KICKED_CLOSE_CODE),
+            reason: reason.to_owned().into(),
+        }))
+        .await
 }
 
 // #[cfg(test)]
@@ -1,127 +1,300 @@
 use std::{
-    hash::{Hash, Hasher},
-    net::IpAddr,
-    sync::Arc,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
 };
 
-use iced::{futures::stream::BoxStream, Subscription};
-use iced_native::subscription::Recipe;
+use futures::stream::{self, BoxStream};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
 
-use tokio::sync::{broadcast, mpsc, watch};
-use tokio::{runtime::Runtime, sync::oneshot};
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
+use tokio::{runtime::Runtime, sync::oneshot, task::JoinHandle};
 
-use crate::{gui::Message, server::server::start_server, utils::await_option};
+use crate::{server::server::start_server, utils::await_option};
+
+use super::{
+    config::{Config, Port},
+    dashboard::{self, CharacterSummary},
+    error::ServerError,
+    moderation::{Action, Moderation},
+    persistence,
+    session::{random_pin, Pins},
+    storage,
+};
 
 pub struct Server {
     status: watch::Receiver<ServerStatus>,
+
+    /// Every character for the GM's dashboard, sent as `ServerMessage::CharactersChanged`
+    characters: watch::Receiver<Vec<CharacterSummary>>,
     server_message_tx: broadcast::Sender<ServerMessage>, // The tx needs to be stored since you have to have the tx to make more rx
     tx: mpsc::UnboundedSender<ServerCommand>,
+    gm_pin: u32,
+
+    /// Made up when the server's made, so subscriptions to it can tell it apart from any other
+    id: u32,
+
+    /// Finishes once the server's stopped and saved everything
+    task: JoinHandle<()>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum ServerCommand {
-    SwitchPort { port: u16 },
+    SwitchPort {
+        port: Port,
+    },
+
+    /// Listen for connections on these addresses, `::` listens on every address
+    SwitchAddresses {
+        addresses: Vec<IpAddr>,
+    },
+
+    /// Require players to enter a PIN to join, or let anyone join if it's None
+    SetTablePin {
+        pin: Option<u32>,
+    },
     Restart,
     Stop,
     Join,
+
+    /// Write a backup of every room to the backups folder in the data directory
+    ExportBackup,
+
+    /// Restore the newest backup in the backups folder
+    ImportBackup,
+
+    /// Close a connection, the id is the one in `ConnectionInfo`
+    Kick {
+        id: u64,
+    },
+
+    /// Close a connection, and keep its player id & IP address out until the app closes
+    Ban {
+        id: u64,
+    },
+
+    /// Stop a connection from changing anything, like a spectator
+    Mute {
+        id: u64,
+    },
+    Unmute {
+        id: u64,
+    },
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum ServerStatus {
-    Online { ip: IpAddr },
-    OnlineNoIp,
+    /// The port is the one that's actually being listened on, which is only known once the server starts if it's picked automatically
+    ///
+    /// The server listens on the same port on every address
+    Online {
+        ip: IpAddr,
+        port: u16,
+        addresses: Vec<IpAddr>,
+    },
+    OnlineNoIp {
+        port: u16,
+        addresses: Vec<IpAddr>,
+    },
     Restarting,
     Offline,
-    Error,
+    Error(ServerError),
+}
+
+impl ServerStatus {
+    /// Where players can connect, one for each address being listened on
+    ///
+    /// Addresses that listen everywhere are shown as the local IP, or left out if it isn't known
+    pub fn urls(&self) -> Vec<String> {
+        let (ip, port, addresses) = match self {
+            ServerStatus::Online {
+                ip,
+                port,
+                addresses,
+            } => (Some(*ip), *port, addresses),
+            ServerStatus::OnlineNoIp { port, addresses } => (None, *port, addresses),
+            _ => return Vec::new(),
+        };
+
+        let mut urls = Vec::new();
+
+        for &address in addresses {
+            let address = match (address.is_unspecified(), ip) {
+                (false, _) => address,
+                (true, Some(ip)) => ip,
+                (true, None) => continue,
+            };
+
+            let url = format!("http://{}", SocketAddr::from((address, port)));
+
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+
+        urls
+    }
+}
+
+/// What a connection is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    Player,
+
+    /// Can see every character and run the game, given to whoever knows the GM PIN
+    Gm,
+
+    /// Can see everything players can, but can't change anything
+    Spectator,
+}
+
+/// Everything the GUI shows about a connection
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// Tells connections apart, since one player can connect more than once
+    pub id: u64,
+    pub player: u32,
+
+    /// The name the player picked, if they picked one
+    pub name: Option<String>,
+    pub role: Role,
+    pub address: SocketAddr,
+    pub connected_since: SystemTime,
+
+    /// How long the last ping took to be answered, None until one has been
+    pub latency: Option<Duration>,
+
+    /// Whether the GM stopped them from changing anything
+    pub muted: bool,
+
+    /// The names of the characters the player owns in their room
+    pub characters: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
 pub enum ServerMessage {
     Status(ServerStatus),
-    NewConnection { id: u32 },
-    ClosedConnection { id: u32 },
+
+    /// A client finished the handshake
+    NewConnection {
+        connection: ConnectionInfo,
+    },
+
+    /// Something about a connection changed, like its role, latency or characters
+    ConnectionChanged {
+        connection: ConnectionInfo,
+    },
+    ClosedConnection {
+        id: u64,
+    },
+
+    /// The name of every room and how many people are in it
+    RoomsChanged {
+        rooms: Vec<(String, usize)>,
+    },
+
+    /// How exporting or importing a backup went
+    BackupFinished {
+        message: String,
+    },
+
+    /// Every character in every room, for the GM's dashboard
+    CharactersChanged {
+        characters: Vec<CharacterSummary>,
+    },
 }
 
 impl Server {
-    /// Create a new server
-    pub fn new(runtime: Arc<Runtime>) -> Server {
+    /// Create a new server with the given settings, it starts straight away if the config says to autostart
+    ///
+    /// The GM PIN is random if it isn't given
+    pub fn new(runtime: Arc<Runtime>, config: Config, gm_pin: Option<u32>) -> Server {
         let (tx, server_rx) = mpsc::unbounded_channel();
         let (server_tx, rx) = watch::channel(ServerStatus::Offline);
         let (server_message_tx, _) = broadcast::channel(32);
+        let (characters_tx, characters) = watch::channel(Vec::new());
 
-        runtime.spawn(run_server(
+        let gm_pin = gm_pin.unwrap_or_else(random_pin);
+
+        let task = runtime.spawn(run_server(
             Arc::clone(&runtime),
             server_rx,
             server_tx,
             server_message_tx.clone(),
+            characters_tx,
+            gm_pin,
+            config.clone(),
         ));
 
+        if config.autostart {
+            tx.send(ServerCommand::Restart).ok();
+        }
+
         Server {
             status: rx,
+            characters,
             tx,
             server_message_tx,
+            gm_pin,
+            id: rand::random(),
+            task,
         }
     }
 
-    /// Create a subscription to the server's status
-    pub fn subscription(&self) -> Subscription<Message> {
-        Subscription::from_recipe(ServerSubscription {
-            id: rand::random(),
-            status: self.status.clone(),
-            server_messages: self.server_message_tx.subscribe(),
-        })
+    /// Stays the same for as long as the server exists, so the GUI keeps the same subscription to it
+    pub fn id(&self) -> u32 {
+        self.id
     }
 
-    /// Send the server a command
-    pub fn send(&self, command: ServerCommand) {
-        self.tx.send(command).ok();
+    /// The PIN players enter to become the GM
+    pub fn gm_pin(&self) -> u32 {
+        self.gm_pin
     }
-}
 
-/// A subscription to the server's status
-struct ServerSubscription {
-    id: u32,
-    status: watch::Receiver<ServerStatus>,
-    server_messages: broadcast::Receiver<ServerMessage>,
-}
-
-/// A Recipe is iced's way of letting you tell the GUI about changes to background tasks
-impl<H: Hasher, I> Recipe<H, I> for ServerSubscription {
-    type Output = Message;
-
-    /// A unique identifier for this recipe instance
-    fn hash(&self, state: &mut H) {
-        struct Marker;
-        std::any::TypeId::of::<Marker>().hash(state);
-
-        self.id.hash(state);
-    }
-
-    /// Stream messages to the GUI, iced will await futures in the stream and send the message to the GUI when they resolve.
+    /// Stream everything that happens on the server, changes to its status are sent as `ServerMessage::Status`
     ///
-    /// In this case, it just awaits server status changes
-    fn stream(self: Box<Self>, _input: BoxStream<I>) -> BoxStream<Self::Output> {
-        Box::pin(iced_futures::futures::stream::unfold(
-            self,
-            move |mut server| async move {
-                let message = tokio::select! {
-                    Ok(_) = server.status.changed() => {
-                        let status = *server.status.borrow();
-
-                        Message::ServerStatus(status)
-                    }
-
-                    Ok(msg) = server.server_messages.recv() => {
-                        Message::ServerMessage(msg)
-                    }
-
-                    else => Message::DoNothing
-                };
-
-                Some((message, server))
+    /// The stream ends once the server has stopped after being told to join
+    pub fn events(&self) -> BoxStream<'static, ServerMessage> {
+        let receivers = (
+            self.status.clone(),
+            self.characters.clone(),
+            self.server_message_tx.subscribe(),
+        );
+
+        Box::pin(stream::unfold(
+            receivers,
+            |(mut status, mut characters, mut messages)| async move {
+                loop {
+                    let message = tokio::select! {
+                        changed = status.changed() => match changed {
+                            Ok(()) => ServerMessage::Status(status.borrow().clone()),
+                            Err(_) => return None,
+                        },
+
+                        Ok(()) = characters.changed() => ServerMessage::CharactersChanged {
+                            characters: characters.borrow_and_update().clone(),
+                        },
+
+                        received = messages.recv() => match received {
+                            Ok(message) => message,
+
+                            // Missing a few messages isn't a big deal, the next ones are still worth sending
+                            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        },
+                    };
+
+                    return Some((message, (status, characters, messages)));
+                }
             },
         ))
     }
+
+    /// Send the server a command
+    pub fn send(&self, command: ServerCommand) {
+        self.tx.send(command).ok();
+    }
 }
 
 /// This function responds to server commands, and sends the server's status
@@ -132,18 +305,47 @@ async fn run_server(
     mut rx: mpsc::UnboundedReceiver<ServerCommand>,
     status_sender: watch::Sender<ServerStatus>,
     server_message_sender: broadcast::Sender<ServerMessage>,
+    characters_sender: watch::Sender<Vec<CharacterSummary>>,
+    gm_pin: u32,
+    config: Config,
 ) {
-    println!("Starting server thread");
+    debug!("Starting server thread");
 
     status_sender.send(ServerStatus::Offline).ok();
 
-    let mut port: u16 = 8000;
+    let mut port = config.port;
+    let mut addresses = config.bind.clone();
 
     // A oneshot that when a value is transmitted, stops the server
     let mut server_canceller: Option<oneshot::Sender<()>> = None;
 
     let mut signal_receiver: Option<mpsc::UnboundedReceiver<ServerMessage>> = None;
 
+    // The PINs are kept outside of the server so they stay the same when it restarts
+    let (pins_sender, pins) = watch::channel(Pins {
+        gm: gm_pin,
+        table: None,
+    });
+
+    // The game is kept out here too, so everything's still there after restarting the server or switching ports
+    let mut rooms = persistence::load(storage::open(config.storage));
+    rooms.configure(&config);
+    let rooms = Arc::new(RwLock::new(rooms));
+
+    // Bans last until the app closes, even if the server restarts
+    let moderation = Arc::new(Mutex::new(Moderation::default()));
+
+    let (stop_saving, stop_saving_receiver) = oneshot::channel();
+    let saver = runtime.spawn(persistence::save_when_changed(
+        Arc::clone(&rooms),
+        stop_saving_receiver,
+    ));
+
+    let dashboard = runtime.spawn(dashboard::report_characters(
+        Arc::clone(&rooms),
+        characters_sender,
+    ));
+
     loop {
         // Wait for either receiving a command, or receiving an update to the server's status
         tokio::select! {
@@ -158,6 +360,14 @@ async fn run_server(
                         port = new_port;
                     }
 
+                    ServerCommand::SwitchAddresses { addresses: new_addresses } => {
+                        addresses = new_addresses;
+                    }
+
+                    ServerCommand::SetTablePin { pin } => {
+                        pins_sender.send_modify(|pins| pins.table = pin);
+                    }
+
                     ServerCommand::Restart => {
                         status_sender.send(ServerStatus::Restarting).ok();
 
@@ -173,7 +383,7 @@ async fn run_server(
 
                         signal_receiver = Some(status_rx);
 
-                        runtime.spawn(start_server(port, cancel_signal, status_tx, Arc::clone(&runtime)));
+                        runtime.spawn(start_server(addresses.clone(), port, config.rate_limits, cancel_signal, status_tx, pins.clone(), Arc::clone(&rooms), Arc::clone(&moderation), Arc::clone(&runtime)));
                     }
 
                     ServerCommand::Stop => {
@@ -184,6 +394,29 @@ async fn run_server(
                     }
 
                     ServerCommand::Join => break,
+
+                    ServerCommand::ExportBackup => {
+                        let message = match persistence::export_backup(&rooms).await {
+                            Ok(path) => format!("Exported a backup to {}", path),
+                            Err(e) => format!("Couldn't export a backup: {}", e),
+                        };
+
+                        server_message_sender.send(ServerMessage::BackupFinished { message }).ok();
+                    }
+
+                    ServerCommand::ImportBackup => {
+                        let message = match persistence::import_latest_backup(&rooms).await {
+                            Ok(path) => format!("Imported the backup from {}", path),
+                            Err(e) => format!("Couldn't import a backup: {}", e),
+                        };
+
+                        server_message_sender.send(ServerMessage::BackupFinished { message }).ok();
+                    }
+
+                    ServerCommand::Kick { id } => moderation.lock().unwrap().send(id, Action::Kick),
+                    ServerCommand::Ban { id } => moderation.lock().unwrap().send(id, Action::Ban),
+                    ServerCommand::Mute { id } => moderation.lock().unwrap().send(id, Action::Mute(true)),
+                    ServerCommand::Unmute { id } => moderation.lock().unwrap().send(id, Action::Mute(false)),
                 }
             }
 
@@ -202,10 +435,18 @@ async fn run_server(
             }
         }
     }
+
+    dashboard.abort();
+
+    // Save everything before the app closes
+    stop_saving.send(()).ok();
+    saver.await.ok();
 }
 
 impl Drop for Server {
+    /// Wait for the server to save everything, the app exits as soon as the GUI's dropped
     fn drop(&mut self) {
         self.tx.send(ServerCommand::Join).ok();
+        futures::executor::block_on(&mut self.task).ok();
     }
 }
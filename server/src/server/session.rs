@@ -0,0 +1,238 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+
+use super::{rooms::JoinedRoom, server::FromClientMessage, ConnectionInfo, Role};
+
+/// How many characters of a player's name are kept
+const MAX_NAME_LENGTH: usize = 32;
+
+/// How many digits the GM PIN has, the table PIN can be up to this long too
+pub const PIN_DIGITS: usize = 8;
+
+/// Everything the server knows about one websocket connection
+pub(super) struct Session {
+    /// Tells the connection apart from others, even ones for the same player
+    pub connection: u64,
+
+    /// The player's id, None until the client does the handshake
+    pub id: Option<u32>,
+
+    /// The name the player picked, if they picked one
+    pub name: Option<String>,
+
+    /// Where the connection is coming from
+    pub address: SocketAddr,
+    pub connected_since: SystemTime,
+
+    /// How long the last ping took to be answered, None until one has been
+    pub latency: Option<Duration>,
+
+    /// Muted sessions can look around but not change anything, like spectators
+    pub muted: bool,
+
+    /// Set when the session tries to say who they are while they're banned, so they're closed before they get an id
+    pub banned: bool,
+
+    /// How many wrong PINs & sync passphrases the connection has entered
+    pub wrong_pins: u32,
+
+    /// The GM role only applies to the room the session claimed it in
+    pub role: Role,
+
+    /// The room for the campaign the client is playing, None until they join one
+    pub room: Option<JoinedRoom>,
+}
+
+/// The PINs shown in the server's GUI
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Pins {
+    /// Entering this PIN makes a connection the GM
+    pub gm: u32,
+
+    /// If set, players have to enter this PIN to join
+    pub table: Option<u32>,
+}
+
+impl Session {
+    pub fn new(connection: u64, address: SocketAddr) -> Session {
+        Session {
+            connection,
+            id: None,
+            name: None,
+            address,
+            connected_since: SystemTime::now(),
+            latency: None,
+            muted: false,
+            banned: false,
+            wrong_pins: 0,
+            role: Role::Player,
+            room: None,
+        }
+    }
+
+    /// What the server's GUI shows about the connection, None before the handshake
+    pub fn info(&self, characters: Vec<String>) -> Option<ConnectionInfo> {
+        Some(ConnectionInfo {
+            id: self.connection,
+            player: self.id?,
+            name: self.name.clone(),
+            role: self.role,
+            address: self.address,
+            connected_since: self.connected_since,
+            latency: self.latency,
+            muted: self.muted,
+            characters,
+        })
+    }
+
+    /// Use the name the player picked, ignoring blank ones and cutting long ones short
+    pub fn set_name(&mut self, name: Option<String>) {
+        self.name = name
+            .map(|v| v.trim().chars().take(MAX_NAME_LENGTH).collect::<String>())
+            .filter(|v| !v.is_empty());
+    }
+
+    pub fn is_gm(&self) -> bool {
+        self.role == Role::Gm
+    }
+
+    pub fn is_spectator(&self) -> bool {
+        self.role == Role::Spectator
+    }
+
+    /// Whether the session is allowed to change a character owned by `owner`
+    pub fn can_edit(&self, owner: u32) -> bool {
+        self.is_gm() || self.id == Some(owner)
+    }
+
+    /// Why the session isn't allowed to send the message, None if it is
+    pub fn refusal(&self, msg: &FromClientMessage) -> Option<&'static str> {
+        // Asking for another id would get around being muted & leave the old id in the room
+        if msg.is_handshake() && self.id.is_some() {
+            return Some("You already joined");
+        }
+
+        if self.is_spectator() && !msg.allowed_for_spectators() {
+            return Some("Spectators can't change anything");
+        }
+
+        // Muted sessions are treated like spectators, besides still being able to finish the handshake
+        if self.muted && !msg.is_handshake() && !msg.allowed_for_spectators() {
+            return Some("The GM muted you, so you can't change anything");
+        }
+
+        if msg.requires_gm() && !self.is_gm() {
+            return Some("Only the GM can do that");
+        }
+
+        None
+    }
+}
+
+impl FromClientMessage {
+    /// Whether the message is how a client says who they are, which they do once before anything else
+    pub fn is_handshake(&self) -> bool {
+        matches!(
+            self,
+            FromClientMessage::RequestId { .. }
+                | FromClientMessage::Id { .. }
+                | FromClientMessage::Spectate { .. }
+        )
+    }
+
+    /// Whether a spectator is allowed to send the message, they can only look around
+    ///
+    /// Syncing campaigns is allowed since it doesn't touch the game
+    pub fn allowed_for_spectators(&self) -> bool {
+        matches!(
+            self,
+            FromClientMessage::JoinRoom { .. }
+                | FromClientMessage::UploadCampaigns { .. }
+                | FromClientMessage::DownloadCampaigns { .. }
+        )
+    }
+
+    /// Whether the message is for running the game, so only the GM is allowed to send it
+    pub fn requires_gm(&self) -> bool {
+        matches!(
+            self,
+            FromClientMessage::SetVisibility { .. }
+                | FromClientMessage::Reveal { .. }
+                | FromClientMessage::StartCombat {}
+                | FromClientMessage::NextTurn {}
+                | FromClientMessage::EndCombat {}
+                | FromClientMessage::ApplyDamage { .. }
+                | FromClientMessage::ApplyHealing { .. }
+                | FromClientMessage::AreaEffect { .. }
+                | FromClientMessage::SaveTemplate { .. }
+                | FromClientMessage::DeleteTemplate { .. }
+                | FromClientMessage::ListTemplates {}
+                | FromClientMessage::SpawnFromTemplate { .. }
+        )
+    }
+}
+
+/// Pick a random PIN with `PIN_DIGITS` digits
+pub(super) fn random_pin() -> u32 {
+    rand::random::<u32>() % 10u32.pow(PIN_DIGITS as u32)
+}
+
+/// Write a PIN with its leading zeros
+pub fn format_pin(pin: u32) -> String {
+    format!("{:0width$}", pin, width = PIN_DIGITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(id: u32) -> Session {
+        let mut session = Session::new(1, "127.0.0.1:1234".parse().unwrap());
+        session.id = Some(id);
+
+        session
+    }
+
+    #[test]
+    fn muted_players_cant_ask_for_a_new_id() {
+        let mut session = joined(7);
+        session.muted = true;
+
+        let request = FromClientMessage::RequestId {
+            pin: None,
+            name: None,
+        };
+
+        assert!(session.refusal(&request).is_some());
+        assert_eq!(session.id, Some(7));
+        assert!(session.muted);
+    }
+
+    #[test]
+    fn only_the_gm_can_run_the_game() {
+        let mut session = joined(7);
+        let next_turn = FromClientMessage::NextTurn {};
+
+        assert!(session.refusal(&next_turn).is_some());
+
+        session.role = Role::Gm;
+        assert_eq!(session.refusal(&next_turn), None);
+
+        session.role = Role::Spectator;
+        assert!(session.refusal(&next_turn).is_some());
+    }
+
+    #[test]
+    fn the_handshake_is_only_done_once() {
+        let session = Session::new(1, "127.0.0.1:1234".parse().unwrap());
+        let request = FromClientMessage::RequestId {
+            pin: None,
+            name: None,
+        };
+
+        assert_eq!(session.refusal(&request), None);
+        assert!(joined(7).refusal(&request).is_some());
+    }
+}
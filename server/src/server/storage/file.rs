@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tracing::warn;
+
+use crate::{server::events::LoggedEvent, utils::write_atomically};
+
+use super::Storage;
+
+/// Keeps the game in JSON files in a directory
+///
+/// The snapshot is `state.json`, events are appended one per line to `events.jsonl` until the log is first rotated and to `events-<sequence>.jsonl` after, players' tokens are in `tokens.json`, checkpoints go in `checkpoints/`, backups go in `backups/`, and synced campaigns go in `synced-campaigns/`
+#[derive(Debug)]
+pub(in crate::server) struct FileStorage {
+    dir: PathBuf,
+
+    /// The newest part of the event log, opened the first time an event is written
+    events: Mutex<Option<File>>,
+
+    /// Held while the tokens are rewritten, so two players' tokens can't be saved over each other
+    tokens: Mutex<()>,
+}
+
+impl FileStorage {
+    pub fn new(dir: PathBuf) -> FileStorage {
+        FileStorage {
+            dir,
+            events: Mutex::new(None),
+            tokens: Mutex::new(()),
+        }
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        self.dir.join("state.json")
+    }
+
+    /// The part of the log with the events after `sequence`
+    fn events_path(&self, sequence: u64) -> PathBuf {
+        match sequence {
+            0 => self.dir.join("events.jsonl"),
+            v => self.dir.join(format!("events-{}.jsonl", v)),
+        }
+    }
+
+    /// Every part of the event log and the sequence number its events come after, oldest first
+    fn event_parts(&self) -> Result<Vec<(u64, PathBuf)>, String> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let mut parts = entries
+            .filter_map(|entry| entry.ok().map(|v| v.path()))
+            .filter(|path| path.extension().is_some_and(|v| v == "jsonl"))
+            .filter_map(|path| {
+                let sequence = match path.file_stem()?.to_str()? {
+                    "events" => 0,
+                    stem => stem.strip_prefix("events-")?.parse::<u64>().ok()?,
+                };
+
+                Some((sequence, path))
+            })
+            .collect::<Vec<_>>();
+
+        parts.sort();
+
+        Ok(parts)
+    }
+
+    fn tokens_path(&self) -> PathBuf {
+        self.dir.join("tokens.json")
+    }
+
+    fn checkpoints_dir(&self) -> PathBuf {
+        self.dir.join("checkpoints")
+    }
+
+    fn backups_dir(&self) -> PathBuf {
+        self.dir.join("backups")
+    }
+
+    fn synced_dir(&self) -> PathBuf {
+        self.dir.join("synced-campaigns")
+    }
+
+    /// Where an identity's campaigns are stored, the identity is hex encoded so it can't be used to write anywhere else
+    fn synced_path(&self, identity: &str) -> PathBuf {
+        let encoded = identity
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        self.synced_dir().join(format!("{}.json", encoded))
+    }
+}
+
+/// The file in the directory named `<prefix><timestamp>.json` with the biggest timestamp that's at most `until`
+fn newest_until(dir: &Path, prefix: &str, until: u64) -> Result<Option<PathBuf>, String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    Ok(entries
+        .filter_map(|entry| entry.ok().map(|v| v.path()))
+        .filter(|path| path.extension().is_some_and(|v| v == "json"))
+        .filter_map(|path| {
+            let timestamp = path
+                .file_stem()?
+                .to_str()?
+                .strip_prefix(prefix)?
+                .parse::<u64>()
+                .ok()?;
+
+            Some((timestamp, path))
+        })
+        .filter(|(timestamp, _)| *timestamp <= until)
+        .max()
+        .map(|(_, path)| path))
+}
+
+/// Open a part of the event log for appending, making it if it doesn't exist yet
+fn open_for_appending(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| e.to_string())
+}
+
+/// Read a file, None if it doesn't exist
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    match fs::read(path) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+impl Storage for FileStorage {
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>, String> {
+        read_if_exists(&self.snapshot_path())
+    }
+
+    fn save_snapshot(&self, snapshot: &[u8]) -> Result<(), String> {
+        write_atomically(&self.snapshot_path(), snapshot).map_err(|e| e.to_string())
+    }
+
+    fn discard_snapshot(&self) -> Result<(), String> {
+        let path = self.snapshot_path();
+
+        fs::rename(&path, path.with_extension("broken.json")).map_err(|e| e.to_string())
+    }
+
+    fn append_event(&self, event: &LoggedEvent) -> Result<(), String> {
+        let mut file = self.events.lock().unwrap();
+
+        if file.is_none() {
+            fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+
+            let newest = self
+                .event_parts()?
+                .pop()
+                .map_or(0, |(sequence, _)| sequence);
+
+            *file = Some(open_for_appending(&self.events_path(newest))?);
+        }
+
+        let mut line = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+
+        file.as_mut()
+            .unwrap()
+            .write_all(&line)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Only the parts of the log that can have events after `sequence` are read
+    ///
+    /// Lines that can't be read are skipped, the last one might've been cut off if the server crashed while writing it
+    fn read_events_after(&self, sequence: u64) -> Result<Vec<LoggedEvent>, String> {
+        let parts = self.event_parts()?;
+
+        // The newest part that starts at or before the sequence number, and every part after it
+        let first = parts
+            .iter()
+            .rposition(|(after, _)| *after <= sequence)
+            .unwrap_or(0);
+
+        let mut events = Vec::new();
+
+        for (_, path) in &parts[first..] {
+            let raw = match read_if_exists(path)? {
+                Some(v) => String::from_utf8_lossy(&v).into_owned(),
+                None => continue,
+            };
+
+            events.extend(
+                raw.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .filter_map(|line| match serde_json::from_str::<LoggedEvent>(line) {
+                        Ok(v) => Some(v),
+                        Err(e) => {
+                            warn!("Skipping an invalid event: {}", e);
+                            None
+                        }
+                    })
+                    .filter(|event| event.sequence > sequence),
+            );
+        }
+
+        Ok(events)
+    }
+
+    fn rotate_events(&self, sequence: u64) -> Result<(), String> {
+        let mut file = self.events.lock().unwrap();
+
+        fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+
+        *file = Some(open_for_appending(&self.events_path(sequence))?);
+
+        Ok(())
+    }
+
+    fn save_checkpoint(&self, timestamp: u64, snapshot: &[u8]) -> Result<(), String> {
+        let path = self
+            .checkpoints_dir()
+            .join(format!("checkpoint-{}.json", timestamp));
+
+        write_atomically(&path, snapshot).map_err(|e| e.to_string())
+    }
+
+    fn checkpoint_before(&self, timestamp: u64) -> Result<Option<Vec<u8>>, String> {
+        match newest_until(&self.checkpoints_dir(), "checkpoint-", timestamp)? {
+            Some(path) => fs::read(path).map(Some).map_err(|e| e.to_string()),
+            None => Ok(None),
+        }
+    }
+
+    fn load_tokens(&self) -> Result<HashMap<u32, String>, String> {
+        match read_if_exists(&self.tokens_path())? {
+            Some(raw) => serde_json::from_slice(&raw).map_err(|e| e.to_string()),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn save_token(&self, id: u32, token: &str) -> Result<(), String> {
+        let _lock = self.tokens.lock().unwrap();
+
+        let mut tokens = self.load_tokens()?;
+        tokens.insert(id, token.to_owned());
+
+        let encoded = serde_json::to_vec(&tokens).map_err(|e| e.to_string())?;
+
+        write_atomically(&self.tokens_path(), &encoded).map_err(|e| e.to_string())
+    }
+
+    fn save_backup(&self, timestamp: u64, backup: &[u8]) -> Result<String, String> {
+        let path = self
+            .backups_dir()
+            .join(format!("backup-{}.json", timestamp));
+
+        write_atomically(&path, backup).map_err(|e| e.to_string())?;
+
+        Ok(path.display().to_string())
+    }
+
+    fn latest_backup(&self) -> Result<Option<(String, Vec<u8>)>, String> {
+        // Backups are named after when they were made, so the newest one has the biggest timestamp
+        match newest_until(&self.backups_dir(), "backup-", u64::MAX)? {
+            Some(path) => {
+                let backup = fs::read(&path).map_err(|e| e.to_string())?;
+
+                Ok(Some((path.display().to_string(), backup)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn load_synced(&self, identity: &str) -> Result<Option<Vec<u8>>, String> {
+        read_if_exists(&self.synced_path(identity))
+    }
+
+    fn save_synced(&self, identity: &str, synced: &[u8]) -> Result<(), String> {
+        write_atomically(&self.synced_path(identity), synced).map_err(|e| e.to_string())
+    }
+
+    fn synced_count(&self) -> Result<usize, String> {
+        match fs::read_dir(self.synced_dir()) {
+            // Temporary files from writes that didn't finish aren't anyone's campaigns
+            Ok(entries) => Ok(entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().extension().is_some_and(|v| v == "json"))
+                .count()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::server::events::LoggedEvent;
+
+use super::Storage;
+
+/// Keeps the game in memory, so nothing is left once the server stops
+///
+/// Useful for trying things out and for tests, since none of the game or synced campaigns touch the disk
+#[derive(Debug, Default)]
+pub(in crate::server) struct MemoryStorage {
+    data: Mutex<MemoryData>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryData {
+    snapshot: Option<Vec<u8>>,
+
+    /// Events encoded as JSON, so they come back out as copies like they would from a real storage
+    events: Vec<String>,
+
+    /// Checkpoints and when they were made, oldest first
+    checkpoints: Vec<(u64, Vec<u8>)>,
+
+    /// Backups and when they were made, oldest first
+    backups: Vec<(u64, Vec<u8>)>,
+
+    tokens: HashMap<u32, String>,
+
+    /// Synced campaigns by identity
+    synced: HashMap<String, Vec<u8>>,
+}
+
+impl Storage for MemoryStorage {
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.data.lock().unwrap().snapshot.clone())
+    }
+
+    fn save_snapshot(&self, snapshot: &[u8]) -> Result<(), String> {
+        self.data.lock().unwrap().snapshot = Some(snapshot.to_vec());
+
+        Ok(())
+    }
+
+    fn discard_snapshot(&self) -> Result<(), String> {
+        self.data.lock().unwrap().snapshot = None;
+
+        Ok(())
+    }
+
+    fn append_event(&self, event: &LoggedEvent) -> Result<(), String> {
+        let encoded = serde_json::to_string(event).map_err(|e| e.to_string())?;
+
+        self.data.lock().unwrap().events.push(encoded);
+
+        Ok(())
+    }
+
+    fn read_events_after(&self, sequence: u64) -> Result<Vec<LoggedEvent>, String> {
+        let events = self
+            .data
+            .lock()
+            .unwrap()
+            .events
+            .iter()
+            .map(|v| serde_json::from_str::<LoggedEvent>(v).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(events
+            .into_iter()
+            .filter(|event| event.sequence > sequence)
+            .collect())
+    }
+
+    fn save_checkpoint(&self, timestamp: u64, snapshot: &[u8]) -> Result<(), String> {
+        self.data
+            .lock()
+            .unwrap()
+            .checkpoints
+            .push((timestamp, snapshot.to_vec()));
+
+        Ok(())
+    }
+
+    fn checkpoint_before(&self, timestamp: u64) -> Result<Option<Vec<u8>>, String> {
+        Ok(self
+            .data
+            .lock()
+            .unwrap()
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(made, _)| *made <= timestamp)
+            .map(|(_, snapshot)| snapshot.clone()))
+    }
+
+    fn save_backup(&self, timestamp: u64, backup: &[u8]) -> Result<String, String> {
+        self.data
+            .lock()
+            .unwrap()
+            .backups
+            .push((timestamp, backup.to_vec()));
+
+        Ok(format!("memory (made at {})", timestamp))
+    }
+
+    fn latest_backup(&self) -> Result<Option<(String, Vec<u8>)>, String> {
+        Ok(self
+            .data
+            .lock()
+            .unwrap()
+            .backups
+            .last()
+            .map(|(timestamp, backup)| (format!("memory (made at {})", timestamp), backup.clone())))
+    }
+
+    fn load_tokens(&self) -> Result<HashMap<u32, String>, String> {
+        Ok(self.data.lock().unwrap().tokens.clone())
+    }
+
+    fn save_token(&self, id: u32, token: &str) -> Result<(), String> {
+        self.data
+            .lock()
+            .unwrap()
+            .tokens
+            .insert(id, token.to_owned());
+
+        Ok(())
+    }
+
+    fn load_synced(&self, identity: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.data.lock().unwrap().synced.get(identity).cloned())
+    }
+
+    fn save_synced(&self, identity: &str, synced: &[u8]) -> Result<(), String> {
+        self.data
+            .lock()
+            .unwrap()
+            .synced
+            .insert(identity.to_owned(), synced.to_vec());
+
+        Ok(())
+    }
+
+    fn synced_count(&self) -> Result<usize, String> {
+        Ok(self.data.lock().unwrap().synced.len())
+    }
+}
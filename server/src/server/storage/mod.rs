@@ -0,0 +1,238 @@
+mod file;
+mod memory;
+mod sqlite;
+
+use std::{collections::HashMap, fmt::Debug, str::FromStr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::utils::get_data_dir;
+
+use super::events::LoggedEvent;
+
+pub(super) use self::{file::FileStorage, memory::MemoryStorage, sqlite::SqliteStorage};
+
+/// Somewhere the server keeps the game between runs
+///
+/// Rooms are stored as snapshots in the save file format, so the storage doesn't need to know what's in them
+///
+/// Characters and templates don't get methods of their own, they're saved as part of their room's snapshot and every change to them is in the event log. Storing them separately too would mean two copies that can disagree, and every change to the game state would need a matching storage method
+///
+/// Every so often a snapshot is also kept as a checkpoint and the event log is rotated, so loading only has to read the events after the newest snapshot, and replaying only the events after the checkpoint before the time it's going back to
+///
+/// Campaigns players sync from their browsers are kept here too. The config, the lock file and the logs are about the app rather than the game, so they're always in the data directory whichever storage is picked
+pub(super) trait Storage: Debug + Send + Sync {
+    /// The last snapshot of every room, None if there isn't one yet
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>, String>;
+
+    fn save_snapshot(&self, snapshot: &[u8]) -> Result<(), String>;
+
+    /// Move a snapshot that can't be read out of the way, so it doesn't get overwritten by the next save
+    fn discard_snapshot(&self) -> Result<(), String>;
+
+    /// Add an event to the end of the event log
+    fn append_event(&self, event: &LoggedEvent) -> Result<(), String>;
+
+    /// Every event in the log after the given sequence number, oldest first
+    fn read_events_after(&self, sequence: u64) -> Result<Vec<LoggedEvent>, String>;
+
+    /// Start a new part of the log for the events after `sequence`, so reading them doesn't have to go through the older parts
+    ///
+    /// Storage that can find events by their sequence number without reading the rest doesn't need to do anything
+    fn rotate_events(&self, _sequence: u64) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Keep a snapshot made at the given time, so `replay` can start from it instead of the beginning of the log
+    fn save_checkpoint(&self, timestamp: u64, snapshot: &[u8]) -> Result<(), String>;
+
+    /// The newest checkpoint made at or before the given time
+    fn checkpoint_before(&self, timestamp: u64) -> Result<Option<Vec<u8>>, String>;
+
+    /// Store a backup made at the given time, returning where it went so it can be shown to the user
+    fn save_backup(&self, timestamp: u64, backup: &[u8]) -> Result<String, String>;
+
+    /// The newest backup, and where it came from
+    fn latest_backup(&self) -> Result<Option<(String, Vec<u8>)>, String>;
+
+    /// The secret token of every player, by their id
+    fn load_tokens(&self) -> Result<HashMap<u32, String>, String>;
+
+    /// Remember a player's token, so they can reconnect after the server restarts
+    fn save_token(&self, id: u32, token: &str) -> Result<(), String>;
+
+    /// The campaigns a player synced under an identity, None if nothing's been synced under it
+    fn load_synced(&self, identity: &str) -> Result<Option<Vec<u8>>, String>;
+
+    fn save_synced(&self, identity: &str, synced: &[u8]) -> Result<(), String>;
+
+    /// How many identities have campaigns synced under them
+    fn synced_count(&self) -> Result<usize, String>;
+}
+
+/// Which kind of storage the server keeps the game in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// JSON files in the data directory
+    #[default]
+    File,
+
+    /// An SQLite database in the data directory
+    Sqlite,
+
+    /// Nothing about the game is kept after the server stops
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StorageKind, String> {
+        match s {
+            "file" => Ok(StorageKind::File),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(format!(
+                "`{}` isn't a kind of storage, it should be file, sqlite or memory",
+                s
+            )),
+        }
+    }
+}
+
+/// Open the kind of storage asked for, falling back to memory if it can't be opened so the game still works
+pub(super) fn open(kind: StorageKind) -> Arc<dyn Storage> {
+    match kind {
+        StorageKind::File => Arc::new(FileStorage::new(get_data_dir())),
+
+        StorageKind::Sqlite => match SqliteStorage::open(&get_data_dir().join("dnd-stuff.sqlite3"))
+        {
+            Ok(v) => Arc::new(v),
+            Err(e) => {
+                error!("Couldn't open the database, nothing will be saved: {}", e);
+                Arc::new(MemoryStorage::default())
+            }
+        },
+
+        StorageKind::Memory => Arc::new(MemoryStorage::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use super::*;
+    use crate::server::events::GameEvent;
+
+    fn left(sequence: u64) -> LoggedEvent {
+        LoggedEvent {
+            sequence,
+            timestamp: sequence * 100,
+            room: "Campaign".to_owned(),
+            event: GameEvent::PlayerLeft { id: 1 },
+        }
+    }
+
+    /// Put one of everything in the storage, and check it all comes back out the same
+    fn round_trip(storage: &dyn Storage) {
+        assert_eq!(storage.load_snapshot().unwrap(), None);
+        storage.save_snapshot(b"first").unwrap();
+        storage.save_snapshot(b"second").unwrap();
+        assert_eq!(storage.load_snapshot().unwrap().unwrap(), b"second");
+
+        for sequence in 1..=2 {
+            storage.append_event(&left(sequence)).unwrap();
+        }
+        storage.rotate_events(2).unwrap();
+        storage.append_event(&left(3)).unwrap();
+
+        let sequences = |after| {
+            storage
+                .read_events_after(after)
+                .unwrap()
+                .iter()
+                .map(|v| v.sequence)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequences(0), [1, 2, 3]);
+        assert_eq!(sequences(1), [2, 3]);
+        assert!(sequences(3).is_empty());
+
+        storage.save_checkpoint(100, b"at 100").unwrap();
+        storage.save_checkpoint(200, b"at 200").unwrap();
+        assert_eq!(storage.checkpoint_before(50).unwrap(), None);
+        assert_eq!(storage.checkpoint_before(150).unwrap().unwrap(), b"at 100");
+        assert_eq!(storage.checkpoint_before(250).unwrap().unwrap(), b"at 200");
+
+        assert_eq!(storage.latest_backup().unwrap(), None);
+        let first = storage.save_backup(100, b"old").unwrap();
+        let second = storage.save_backup(200, b"new").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            storage.latest_backup().unwrap().unwrap(),
+            (second, b"new".to_vec())
+        );
+
+        storage.save_token(1, "one").unwrap();
+        storage.save_token(2, "two").unwrap();
+        storage.save_token(1, "uno").unwrap();
+        assert_eq!(
+            storage.load_tokens().unwrap(),
+            HashMap::from([(1, "uno".to_owned()), (2, "two".to_owned())])
+        );
+
+        assert_eq!(storage.synced_count().unwrap(), 0);
+        assert_eq!(storage.load_synced("../Alice").unwrap(), None);
+        storage.save_synced("../Alice", b"campaigns").unwrap();
+        storage.save_synced("Bob", b"more campaigns").unwrap();
+        assert_eq!(
+            storage.load_synced("../Alice").unwrap().unwrap(),
+            b"campaigns"
+        );
+        assert_eq!(storage.synced_count().unwrap(), 2);
+
+        storage.discard_snapshot().unwrap();
+        assert_eq!(storage.load_snapshot().unwrap(), None);
+    }
+
+    /// An empty directory that's deleted once the test's done with it
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            TempDir(env::temp_dir().join(format!("dnd-stuff-test-{}", rand::random::<u64>())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn file_storage_round_trips() {
+        let dir = TempDir::new();
+
+        round_trip(&FileStorage::new(dir.0.clone()));
+
+        // A write that never finished doesn't count as someone's campaigns
+        fs::write(dir.0.join("synced-campaigns").join("426f62.1234.tmp"), "").unwrap();
+        assert_eq!(FileStorage::new(dir.0.clone()).synced_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn sqlite_storage_round_trips() {
+        let dir = TempDir::new();
+
+        round_trip(&SqliteStorage::open(&dir.0.join("test.sqlite3")).unwrap());
+    }
+
+    #[test]
+    fn memory_storage_round_trips() {
+        round_trip(&MemoryStorage::default());
+    }
+}
@@ -0,0 +1,261 @@
+use std::{collections::HashMap, fs, path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::warn;
+
+use crate::server::events::LoggedEvent;
+
+use super::Storage;
+
+/// Keeps the game in an SQLite database, so everything's in one file that's written safely
+#[derive(Debug)]
+pub(in crate::server) struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Open the database, making it and its tables if they don't exist yet
+    pub fn open(path: &Path) -> Result<SqliteStorage, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+
+        let connection = Connection::open(path).map_err(|e| e.to_string())?;
+
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS snapshot (
+                    id INTEGER PRIMARY KEY CHECK (id = 0),
+                    data BLOB NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS discarded_snapshots (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    data BLOB NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS events (
+                    sequence INTEGER PRIMARY KEY,
+                    event TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS tokens (
+                    id INTEGER PRIMARY KEY,
+                    token TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS checkpoints (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    timestamp INTEGER NOT NULL,
+                    data BLOB NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS backups (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    timestamp INTEGER NOT NULL,
+                    data BLOB NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS synced_campaigns (
+                    identity TEXT PRIMARY KEY,
+                    data BLOB NOT NULL
+                );",
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_snapshot(&self) -> Result<Option<Vec<u8>>, String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT data FROM snapshot WHERE id = 0", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_snapshot(&self, snapshot: &[u8]) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO snapshot (id, data) VALUES (0, ?1)",
+                params![snapshot],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn discard_snapshot(&self) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "BEGIN;
+                INSERT INTO discarded_snapshots (data) SELECT data FROM snapshot;
+                DELETE FROM snapshot;
+                COMMIT;",
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    fn append_event(&self, event: &LoggedEvent) -> Result<(), String> {
+        let encoded = serde_json::to_string(event).map_err(|e| e.to_string())?;
+
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO events (sequence, event) VALUES (?1, ?2)",
+                params![event.sequence, encoded],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn read_events_after(&self, sequence: u64) -> Result<Vec<LoggedEvent>, String> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection
+            .prepare("SELECT event FROM events WHERE sequence > ?1 ORDER BY sequence")
+            .map_err(|e| e.to_string())?;
+
+        let rows = statement
+            .query_map(params![sequence], |row| row.get::<_, String>(0))
+            .map_err(|e| e.to_string())?;
+
+        let mut events = Vec::new();
+
+        for row in rows {
+            let encoded = row.map_err(|e| e.to_string())?;
+
+            match serde_json::from_str(&encoded) {
+                Ok(v) => events.push(v),
+                Err(e) => warn!("Skipping an invalid event: {}", e),
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn save_checkpoint(&self, timestamp: u64, snapshot: &[u8]) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO checkpoints (timestamp, data) VALUES (?1, ?2)",
+                params![timestamp, snapshot],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn checkpoint_before(&self, timestamp: u64) -> Result<Option<Vec<u8>>, String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM checkpoints WHERE timestamp <= ?1 ORDER BY timestamp DESC, id DESC LIMIT 1",
+                params![timestamp],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    fn load_tokens(&self) -> Result<HashMap<u32, String>, String> {
+        let connection = self.connection.lock().unwrap();
+
+        let mut statement = connection
+            .prepare("SELECT id, token FROM tokens")
+            .map_err(|e| e.to_string())?;
+
+        let rows = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(|e| e.to_string())?;
+
+        rows.collect::<Result<_, _>>().map_err(|e| e.to_string())
+    }
+
+    fn save_token(&self, id: u32, token: &str) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO tokens (id, token) VALUES (?1, ?2)",
+                params![id, token],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_backup(&self, timestamp: u64, backup: &[u8]) -> Result<String, String> {
+        let connection = self.connection.lock().unwrap();
+
+        connection
+            .execute(
+                "INSERT INTO backups (timestamp, data) VALUES (?1, ?2)",
+                params![timestamp, backup],
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(format!(
+            "the database (backup {})",
+            connection.last_insert_rowid()
+        ))
+    }
+
+    fn latest_backup(&self) -> Result<Option<(String, Vec<u8>)>, String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id, data FROM backups ORDER BY id DESC LIMIT 1",
+                [],
+                |row| {
+                    Ok((
+                        format!("the database (backup {})", row.get::<_, i64>(0)?),
+                        row.get(1)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    fn load_synced(&self, identity: &str) -> Result<Option<Vec<u8>>, String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM synced_campaigns WHERE identity = ?1",
+                params![identity],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_synced(&self, identity: &str, synced: &[u8]) -> Result<(), String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO synced_campaigns (identity, data) VALUES (?1, ?2)",
+                params![identity, synced],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn synced_count(&self) -> Result<usize, String> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM synced_campaigns", [], |row| {
+                row.get(0)
+            })
+            .map_err(|e| e.to_string())
+    }
+}
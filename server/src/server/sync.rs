@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::storage::Storage;
+
+/// Bump this whenever the format of synced campaigns changes
+const SCHEMA_VERSION: u64 = 2;
+
+/// The most campaign data one identity can store, in bytes of JSON
+const MAX_CAMPAIGNS_SIZE: usize = 4 * 1024 * 1024;
+
+/// The most identities the server keeps campaigns for, so it can't be filled up by making new ones
+const MAX_IDENTITIES: usize = 1000;
+
+/// A lock for each identity that's being uploaded to, so two uploads can't both pass the conflict check and the second overwrite the first
+static UPLOADING: LazyLock<Mutex<HashMap<String, Arc<Mutex<()>>>>> =
+    LazyLock::new(Default::default);
+
+/// The campaigns a player keeps in their browser's local storage, stored on the server so they can get them back from any browser
+#[derive(Serialize, Deserialize)]
+struct SyncedCampaigns {
+    version: u64,
+
+    /// Anyone who knows the identity & passphrase can read & replace the campaigns
+    ///
+    /// Only the salted hash is kept, so reading the data directory doesn't give away anyone's passphrase
+    passphrase_hash: String,
+
+    /// When the campaigns were last changed in the browser that uploaded them, in milliseconds since the unix epoch
+    modified: u64,
+
+    /// The campaigns in the same format as the website stores them
+    campaigns: Value,
+}
+
+#[derive(Debug)]
+pub(super) enum UploadOutcome {
+    Uploaded,
+
+    /// Both the server's copy and the browser's copy changed since the browser last synced, so uploading would lose data
+    Conflict {
+        server_modified: u64,
+    },
+}
+
+/// Why syncing didn't work
+#[derive(Debug)]
+pub(super) enum SyncError {
+    WrongPassphrase,
+    Other(String),
+}
+
+impl From<String> for SyncError {
+    fn from(message: String) -> SyncError {
+        SyncError::Other(message)
+    }
+}
+
+impl SyncError {
+    pub fn message(self) -> String {
+        match self {
+            SyncError::WrongPassphrase => "That passphrase is wrong".to_owned(),
+            SyncError::Other(message) => message,
+        }
+    }
+}
+
+/// Read a player's synced campaigns, checking their passphrase
+fn read(
+    storage: &dyn Storage,
+    identity: &str,
+    passphrase: &str,
+) -> Result<Option<SyncedCampaigns>, SyncError> {
+    if identity.is_empty() {
+        return Err("Pick a name to sync your campaigns under".to_owned().into());
+    }
+
+    let raw = match storage.load_synced(identity) {
+        Ok(Some(v)) => v,
+        Ok(None) => return Ok(None),
+        Err(e) => return Err(format!("Couldn't read your synced campaigns: {}", e).into()),
+    };
+
+    let synced: SyncedCampaigns = serde_json::from_slice(&raw)
+        .map_err(|e| format!("Your synced campaigns are invalid: {}", e))?;
+
+    if synced.version > SCHEMA_VERSION {
+        return Err(
+            "Your campaigns were synced by a newer version of the server"
+                .to_owned()
+                .into(),
+        );
+    }
+
+    let correct = PasswordHash::new(&synced.passphrase_hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(passphrase.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false);
+
+    if !correct {
+        return Err(SyncError::WrongPassphrase);
+    }
+
+    Ok(Some(synced))
+}
+
+/// Store a player's campaigns, unless the server has changes the browser hasn't seen yet
+///
+/// `last_synced` is the modified time of the server's copy the last time the browser synced, None if it never has
+///
+/// Reading & writing the storage and hashing the passphrase are slow, so it's done off of the async runtime
+pub(super) async fn upload(
+    storage: Arc<dyn Storage>,
+    identity: String,
+    passphrase: String,
+    campaigns: String,
+    modified: u64,
+    last_synced: Option<u64>,
+    force: bool,
+) -> Result<UploadOutcome, SyncError> {
+    if campaigns.len() > MAX_CAMPAIGNS_SIZE {
+        return Err(format!(
+            "Your campaigns are too big to sync, the most is {} MB",
+            MAX_CAMPAIGNS_SIZE / 1024 / 1024
+        )
+        .into());
+    }
+
+    tokio::task::spawn_blocking(move || {
+        upload_blocking(
+            &*storage,
+            &identity,
+            &passphrase,
+            &campaigns,
+            modified,
+            last_synced,
+            force,
+        )
+    })
+    .await
+    .map_err(|e| SyncError::Other(e.to_string()))?
+}
+
+fn upload_blocking(
+    storage: &dyn Storage,
+    identity: &str,
+    passphrase: &str,
+    campaigns: &str,
+    modified: u64,
+    last_synced: Option<u64>,
+    force: bool,
+) -> Result<UploadOutcome, SyncError> {
+    let lock = Arc::clone(
+        UPLOADING
+            .lock()
+            .unwrap()
+            .entry(identity.to_owned())
+            .or_default(),
+    );
+
+    let outcome = {
+        let _uploading = lock.lock().unwrap();
+
+        upload_locked(
+            storage,
+            identity,
+            passphrase,
+            campaigns,
+            modified,
+            last_synced,
+            force,
+        )
+    };
+
+    // Nobody else is waiting to upload to the identity, so its lock can go
+    let mut uploading = UPLOADING.lock().unwrap();
+
+    if Arc::strong_count(&lock) == 2 {
+        uploading.remove(identity);
+    }
+
+    outcome
+}
+
+/// Only called while holding the identity's lock
+fn upload_locked(
+    storage: &dyn Storage,
+    identity: &str,
+    passphrase: &str,
+    campaigns: &str,
+    modified: u64,
+    last_synced: Option<u64>,
+    force: bool,
+) -> Result<UploadOutcome, SyncError> {
+    let existing = read(storage, identity, passphrase)?;
+
+    match existing {
+        Some(existing) => {
+            if !force && Some(existing.modified) != last_synced {
+                return Ok(UploadOutcome::Conflict {
+                    server_modified: existing.modified,
+                });
+            }
+        }
+
+        None => {
+            if storage.synced_count()? >= MAX_IDENTITIES {
+                return Err("The server can't sync any more players' campaigns"
+                    .to_owned()
+                    .into());
+            }
+        }
+    }
+
+    let passphrase_hash = Argon2::default()
+        .hash_password(passphrase.as_bytes(), &SaltString::generate(&mut OsRng))
+        .map_err(|e| format!("Couldn't save your campaigns: {}", e))?
+        .to_string();
+
+    let synced = SyncedCampaigns {
+        version: SCHEMA_VERSION,
+        passphrase_hash,
+        modified,
+        campaigns: serde_json::from_str(campaigns)
+            .map_err(|e| format!("Those campaigns are invalid: {}", e))?,
+    };
+
+    let serialized = serde_json::to_vec(&synced).map_err(|e| e.to_string())?;
+
+    storage
+        .save_synced(identity, &serialized)
+        .map_err(|e| format!("Couldn't save your campaigns: {}", e))?;
+
+    Ok(UploadOutcome::Uploaded)
+}
+
+/// Get a player's campaigns and when they were last changed
+pub(super) async fn download(
+    storage: Arc<dyn Storage>,
+    identity: String,
+    passphrase: String,
+) -> Result<(String, u64), SyncError> {
+    let synced = tokio::task::spawn_blocking(move || read(&*storage, &identity, &passphrase))
+        .await
+        .map_err(|e| SyncError::Other(e.to_string()))??;
+
+    match synced {
+        Some(synced) => Ok((synced.campaigns.to_string(), synced.modified)),
+        None => Err("There aren't any campaigns synced under that name"
+            .to_owned()
+            .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::server::storage::MemoryStorage;
+
+    fn upload(
+        storage: &dyn Storage,
+        passphrase: &str,
+        modified: u64,
+        last_synced: Option<u64>,
+    ) -> Result<UploadOutcome, SyncError> {
+        upload_blocking(
+            storage,
+            "Alice",
+            passphrase,
+            r#"{"campaign":1}"#,
+            modified,
+            last_synced,
+            false,
+        )
+    }
+
+    #[test]
+    fn uploaded_campaigns_come_back_with_the_passphrase() {
+        let storage = MemoryStorage::default();
+
+        assert!(matches!(
+            upload(&storage, "secret", 5, None),
+            Ok(UploadOutcome::Uploaded)
+        ));
+
+        let synced = read(&storage, "Alice", "secret").unwrap().unwrap();
+        assert_eq!(synced.modified, 5);
+        assert_eq!(synced.campaigns.to_string(), r#"{"campaign":1}"#);
+
+        // Only the hash is stored
+        let raw = storage.load_synced("Alice").unwrap().unwrap();
+        assert!(!String::from_utf8(raw).unwrap().contains("secret"));
+
+        assert!(matches!(
+            read(&storage, "Alice", "wrong"),
+            Err(SyncError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            upload(&storage, "wrong", 6, Some(5)),
+            Err(SyncError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn uploading_over_changes_the_browser_hasnt_seen_is_a_conflict() {
+        let storage = MemoryStorage::default();
+
+        upload(&storage, "secret", 5, None).unwrap();
+
+        assert!(matches!(
+            upload(&storage, "secret", 7, Some(3)),
+            Ok(UploadOutcome::Conflict { server_modified: 5 })
+        ));
+        assert!(matches!(
+            upload(&storage, "secret", 7, Some(5)),
+            Ok(UploadOutcome::Uploaded)
+        ));
+    }
+
+    #[test]
+    fn uploads_at_the_same_time_dont_overwrite_each_other() {
+        let storage = MemoryStorage::default();
+
+        // Both think nothing's been synced yet, so only the first one to get there can upload
+        let outcomes = thread::scope(|scope| {
+            let uploads = [1, 2].map(|modified| {
+                let storage = &storage;
+                scope.spawn(move || upload(storage, "secret", modified, None))
+            });
+
+            uploads.map(|v| v.join().unwrap().unwrap())
+        });
+
+        let uploaded = outcomes
+            .iter()
+            .filter(|v| matches!(v, UploadOutcome::Uploaded))
+            .count();
+
+        assert_eq!(uploaded, 1);
+    }
+}
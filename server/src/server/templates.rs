@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::dice::{Dice, RollLogEntry};
+
+/// The most NPCs that can be spawned from a template at once
+pub(super) const MAX_SPAWN: u32 = 50;
+
+/// A preset NPC the GM can spawn a pack of at once
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct NpcTemplate {
+    pub name: String,
+
+    /// What to call each NPC, `{n}` is replaced with the NPC's number. Defaults to `<name> {n}`
+    ///
+    /// The number's added to the end if the pattern doesn't have `{n}`, otherwise every NPC would have the same name
+    #[serde(default)]
+    pub name_pattern: Option<String>,
+
+    /// A dice expression each NPC rolls for its HP, like `2d6`
+    pub hp: String,
+
+    pub initiative_modifier: i64,
+
+    /// Items in the same format the website stores them in
+    #[serde(default)]
+    pub items: Vec<Value>,
+
+    /// Spells in the same format the website stores them in
+    #[serde(default)]
+    pub spells: Vec<Value>,
+}
+
+/// An NPC made from a template
+pub(super) struct SpawnedNpc {
+    pub name: String,
+    pub data: String,
+    pub rolls: Vec<RollLogEntry>,
+}
+
+impl NpcTemplate {
+    /// The name of the NPC with the given number
+    pub fn name_of(&self, number: u32) -> String {
+        match &self.name_pattern {
+            Some(pattern) if pattern.contains("{n}") => pattern.replace("{n}", &number.to_string()),
+            Some(pattern) => format!("{} {}", pattern, number),
+            None => format!("{} {}", self.name, number),
+        }
+    }
+
+    /// Make an NPC with individually rolled HP & initiative, returns None if the HP isn't valid dice syntax
+    pub fn spawn(&self, name: String, owner: u32) -> Option<SpawnedNpc> {
+        let hp_dice = Dice::parse(&self.hp)?;
+
+        // Nothing spawns already dead
+        let hp = hp_dice.roll().max(1);
+
+        let initiative = Dice::d20().roll().saturating_add(self.initiative_modifier);
+
+        let no_spell_slots = [0; 9];
+
+        let data = json!({
+            "owner": owner,
+            "name": name,
+            "hp": hp,
+            "hp_max": hp,
+            "initiative": initiative,
+            "items": self.items,
+            "spells": self.spells,
+            "spellSlots": no_spell_slots,
+            "currentSpellSlots": no_spell_slots,
+        });
+
+        Some(SpawnedNpc {
+            rolls: vec![
+                RollLogEntry::new(format!("{} HP", name), &self.hp, hp),
+                RollLogEntry::new(
+                    format!("{} initiative", name),
+                    &format!("d20 + {}", self.initiative_modifier),
+                    initiative,
+                ),
+            ],
+            name,
+            data: data.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_helpers::object;
+
+    fn goblin(hp: &str, initiative_modifier: i64) -> NpcTemplate {
+        NpcTemplate {
+            name: "Goblin".to_owned(),
+            name_pattern: None,
+            hp: hp.to_owned(),
+            initiative_modifier,
+            items: Vec::new(),
+            spells: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn huge_initiative_modifiers_dont_overflow() {
+        let npc = goblin("2d6", i64::MAX)
+            .spawn("Goblin 1".to_owned(), 1)
+            .unwrap();
+
+        let data = object(serde_json::from_str(&npc.data).unwrap());
+
+        assert_eq!(data["initiative"], i64::MAX);
+    }
+
+    #[test]
+    fn bad_hp_doesnt_spawn() {
+        assert!(goblin("2d", 0).spawn("Goblin 1".to_owned(), 1).is_none());
+    }
+}
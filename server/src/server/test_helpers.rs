@@ -0,0 +1,47 @@
+//! Small builders shared by the unit tests
+
+use serde_json::{json, Map, Value};
+
+use super::{
+    conditions::{Condition, Duration, Expiry},
+    game::CharacterState,
+    visibility::Visibility,
+};
+
+/// A character's data as the JSON object the client sends
+pub fn object(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(obj) => obj,
+        v => panic!("{} isn't an object", v),
+    }
+}
+
+/// A list of character names, like a turn order
+pub fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|v| v.to_string()).collect()
+}
+
+/// A revealed character owned by player 1, with an initiative if it's rolled one
+pub fn character(name: &str, initiative: Option<i64>) -> CharacterState {
+    let data = match initiative {
+        Some(v) => json!({ "name": name, "initiative": v }),
+        None => json!({ "name": name }),
+    };
+
+    CharacterState::new(1, data.to_string(), Visibility::Revealed)
+}
+
+/// A condition with all of its duration left
+pub fn condition(name: &str, duration: Duration, expires: Expiry) -> Condition {
+    let mut condition = Condition {
+        name: name.to_owned(),
+        source: "Test".to_owned(),
+        duration,
+        expires,
+        remaining: 0,
+    };
+
+    condition.reset();
+
+    condition
+}
@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{
+    game::{CharacterState, GameState},
+    server::{InternalMessage, ToClientMessage},
+    session::Session,
+};
+
+/// Which players can see a character, the character's owner and the GM can always see it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) enum Visibility {
+    /// Only the owner knows the character exists, for NPCs the GM is preparing in advance
+    Hidden,
+
+    /// Everyone can see the character
+    #[default]
+    Revealed,
+
+    /// Everyone can see that the character exists, but not its stats
+    Redacted,
+}
+
+/// The fields of a character that are still sent when it's redacted
+const PUBLIC_FIELDS: [&str; 3] = ["name", "owner", "initiative"];
+
+impl CharacterState {
+    /// How the character appears to the session
+    pub fn visibility_for(&self, session: &Session) -> Visibility {
+        if session.can_edit(self.owner) {
+            Visibility::Revealed
+        } else {
+            self.visibility
+        }
+    }
+
+    /// The character's data as the session is allowed to see it, None if they can't see the character
+    pub fn data_for(&self, session: &Session) -> Option<String> {
+        match self.visibility_for(session) {
+            Visibility::Hidden => None,
+            Visibility::Revealed => Some(self.data.clone()),
+            Visibility::Redacted => Some(redact(&self.data)),
+        }
+    }
+}
+
+/// Remove everything but the public fields from a character's data
+fn redact(data: &str) -> String {
+    let obj = match serde_json::from_str(data) {
+        Ok(Value::Object(obj)) => obj,
+        _ => return "{}".to_owned(),
+    };
+
+    let redacted = obj
+        .into_iter()
+        .filter(|(key, _)| PUBLIC_FIELDS.contains(&key.as_str()))
+        .collect::<Map<_, _>>();
+
+    Value::Object(redacted).to_string()
+}
+
+/// Turn an internal message into what the session is allowed to know about it
+///
+/// Every message sent to every connection goes through here, so players can't see characters hidden from them
+pub(super) fn message_for(
+    msg: InternalMessage,
+    session: &Session,
+    state: &GameState,
+) -> Option<ToClientMessage> {
+    let visibility_of = |name: &str| {
+        state
+            .characters
+            .get(name)
+            .map(|character| character.visibility_for(session))
+            .unwrap_or(Visibility::Hidden)
+    };
+
+    Some(match msg {
+        InternalMessage::CharacterUpdated { name, overwrite } => {
+            let character = state.characters.get(&name)?;
+
+            ToClientMessage::CharacterUpdated {
+                data: character.data_for(session)?,
+                player_id: character.owner,
+                overwrite,
+            }
+        }
+
+        InternalMessage::VisibilityChanged {
+            name,
+            owner,
+            previous,
+        } => {
+            if let Some(character) = state.characters.get(&name) {
+                if let Some(data) = character.data_for(session) {
+                    return Some(ToClientMessage::CharacterUpdated {
+                        data,
+                        player_id: character.owner,
+                        overwrite: false,
+                    });
+                }
+            }
+
+            // Sessions that never knew about the character aren't told its name
+            if !session.can_edit(owner) && previous == Visibility::Hidden {
+                return None;
+            }
+
+            ToClientMessage::CharacterRemoved { name }
+        }
+
+        InternalMessage::TurnChanged { round, character } => ToClientMessage::TurnChanged {
+            round,
+            // Players shouldn't find out about a hidden creature because it took a turn
+            character: character.filter(|v| visibility_of(v) != Visibility::Hidden),
+        },
+
+        InternalMessage::ConditionsUpdated {
+            character,
+            conditions,
+        } => {
+            if visibility_of(&character) == Visibility::Hidden {
+                return None;
+            }
+
+            ToClientMessage::ConditionsUpdated {
+                character,
+                conditions,
+            }
+        }
+
+        InternalMessage::ConditionExpired {
+            character,
+            condition,
+        } => {
+            if visibility_of(&character) == Visibility::Hidden {
+                return None;
+            }
+
+            ToClientMessage::ConditionExpired {
+                character,
+                condition,
+            }
+        }
+
+        // The amount of damage dealt gives away a character's HP, so it's only shown to people who can see the stats
+        InternalMessage::DamageApplied { entry } => {
+            if visibility_of(&entry.character) != Visibility::Revealed {
+                return None;
+            }
+
+            ToClientMessage::DamageApplied { entry }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Role;
+
+    /// A GM, the owner of every test character, and another player
+    fn sessions() -> [Session; 3] {
+        let address = "127.0.0.1:1234".parse().unwrap();
+
+        let mut gm = Session::new(1, address);
+        gm.id = Some(9);
+        gm.role = Role::Gm;
+
+        let mut owner = Session::new(2, address);
+        owner.id = Some(1);
+
+        let mut other = Session::new(3, address);
+        other.id = Some(2);
+
+        [gm, owner, other]
+    }
+
+    const GOBLIN: &str = r#"{"name":"Goblin","hp":7}"#;
+
+    fn with_goblin(visibility: Visibility) -> GameState {
+        let mut state = GameState::default();
+        state.characters.insert(
+            "Goblin".to_owned(),
+            CharacterState::new(1, GOBLIN.to_owned(), visibility),
+        );
+
+        state
+    }
+
+    fn changed(previous: Visibility) -> InternalMessage {
+        InternalMessage::VisibilityChanged {
+            name: "Goblin".to_owned(),
+            owner: 1,
+            previous,
+        }
+    }
+
+    #[test]
+    fn hidden_characters_are_only_sent_to_the_gm_and_owner() {
+        let state = with_goblin(Visibility::Hidden);
+        let [gm, owner, other] = sessions();
+
+        for session in [&gm, &owner] {
+            assert!(matches!(
+                message_for(changed(Visibility::Revealed), session, &state),
+                Some(ToClientMessage::CharacterUpdated { .. })
+            ));
+        }
+
+        assert!(matches!(
+            message_for(changed(Visibility::Revealed), &other, &state),
+            Some(ToClientMessage::CharacterRemoved { .. })
+        ));
+
+        // Hiding it again doesn't give its name away to players who never saw it
+        assert!(message_for(changed(Visibility::Hidden), &other, &state).is_none());
+    }
+
+    #[test]
+    fn redacted_characters_only_show_their_public_fields() {
+        let state = with_goblin(Visibility::Redacted);
+        let [gm, _, other] = sessions();
+
+        let data_for =
+            |session: &Session| match message_for(changed(Visibility::Hidden), session, &state) {
+                Some(ToClientMessage::CharacterUpdated { data, .. }) => data,
+                v => panic!("Expected the character, got {:?}", v),
+            };
+
+        assert_eq!(data_for(&gm), GOBLIN);
+        assert_eq!(data_for(&other), r#"{"name":"Goblin"}"#);
+    }
+
+    #[test]
+    fn removed_characters_are_only_removed_for_sessions_that_saw_them() {
+        let state = GameState::default();
+        let [gm, owner, other] = sessions();
+
+        for session in [&gm, &owner, &other] {
+            assert!(matches!(
+                message_for(changed(Visibility::Redacted), session, &state),
+                Some(ToClientMessage::CharacterRemoved { .. })
+            ));
+        }
+
+        assert!(message_for(changed(Visibility::Hidden), &other, &state).is_none());
+
+        for session in [&gm, &owner] {
+            assert!(matches!(
+                message_for(changed(Visibility::Hidden), session, &state),
+                Some(ToClientMessage::CharacterRemoved { .. })
+            ));
+        }
+    }
+}
@@ -1,21 +1,35 @@
-use std::{collections::HashMap, sync::Arc};
+use std::sync::{Arc, Mutex};
 
+use futures::SinkExt;
 use hyper::upgrade::Upgraded;
 use hyper_tungstenite::{
     tungstenite::{Error, Message},
     WebSocketStream,
 };
-use iced::futures::SinkExt;
-use tokio::sync::{broadcast, mpsc::UnboundedSender, RwLock};
+use tokio::sync::{broadcast, mpsc::UnboundedSender, watch, RwLock};
+use tracing::{info, trace, warn, Span};
 
 use crate::server::server::FromClientMessage;
 
 use super::{
-    server::{InternalMessage, ToClientMessage},
+    area_effect::{resolve_target, Ability},
+    conditions::Condition,
+    damage::{apply_damage, apply_healing, DamageLogEntry},
+    dice::{Dice, RollLogEntry},
+    events::GameEvent,
+    game::{CharacterState, GameState, TurnOutcome},
+    moderation::{Moderation, PinKind, MAX_WRONG_PINS_PER_CONNECTION},
+    rooms::{remove_characters_later, Rooms},
+    server::{InternalMessage, SentCharacter, ToClientMessage},
+    session::{Pins, Session},
+    sync::{self, SyncError, UploadOutcome},
+    templates::MAX_SPAWN,
+    visibility::{message_for, Visibility},
+    Role,
     ServerMessage::{self, *},
 };
 
-async fn send(
+pub(super) async fn send(
     websocket: &mut WebSocketStream<Upgraded>,
     message: ToClientMessage,
 ) -> Result<(), Error> {
@@ -24,60 +38,415 @@ async fn send(
         .await
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(super) async fn on_message(
     message: Result<Message, Error>,
     websocket: &mut WebSocketStream<Upgraded>,
-    id: &mut Option<u32>,
+    session: &mut Session,
+    internal_message_receiver: &mut Option<broadcast::Receiver<InternalMessage>>,
     signal_sender: &UnboundedSender<ServerMessage>,
-    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
-    character_states: &Arc<RwLock<HashMap<String, (u32, String)>>>,
+    rooms: &Arc<RwLock<Rooms>>,
+    pins: &watch::Receiver<Pins>,
+    moderation: &Mutex<Moderation>,
 ) -> Result<(), Error> {
-    match message? {
-        Message::Text(msg_raw) => {
-            // println!("{}", msg_raw);
-            let msg: FromClientMessage = match serde_json::from_str(&msg_raw) {
-                Ok(v) => v,
-                Err(_) => return Ok(()),
+    // Leaving is handled once the connection ends, since connections can also drop without saying goodbye
+    if let Message::Text(msg_raw) = message? {
+        let msg: FromClientMessage = match serde_json::from_str(&msg_raw) {
+            Ok(v) => v,
+            Err(_) => return Ok(()),
+        };
+
+        // Everything besides the handshake needs the client to have joined first
+        let is_handshake = msg.is_handshake();
+
+        if !is_handshake && session.id.is_none() {
+            return Ok(());
+        }
+
+        // Banned players are turned away before they're given an id, by their address or the id they say they had
+        if is_handshake {
+            let claimed = match &msg {
+                FromClientMessage::Id { id, .. } => Some(*id),
+                _ => None,
             };
 
-            match msg {
-                FromClientMessage::RequestId {} => {
-                    requested_id(websocket, character_states, id, signal_sender).await?
+            let moderation = moderation.lock().unwrap();
+
+            if moderation.is_address_banned(session.address.ip())
+                || claimed.is_some_and(|id| moderation.is_player_banned(id))
+            {
+                session.banned = true;
+                return Ok(());
+            }
+        }
+
+        if let Some(message) = session.refusal(&msg) {
+            return send(
+                websocket,
+                ToClientMessage::Error {
+                    message: message.to_owned(),
+                },
+            )
+            .await;
+        }
+
+        match msg {
+            FromClientMessage::RequestId { pin, name } => {
+                if table_pin_accepted(websocket, pin, session, pins, moderation).await? {
+                    session.set_name(name);
+                    requested_id(websocket, session, signal_sender, rooms, moderation).await?
+                }
+
+                return Ok(());
+            }
+
+            FromClientMessage::Id {
+                id: new_id,
+                token,
+                pin,
+                name,
+            } => {
+                if table_pin_accepted(websocket, pin, session, pins, moderation).await? {
+                    session.set_name(name);
+                    received_id(
+                        websocket,
+                        new_id,
+                        &token,
+                        session,
+                        signal_sender,
+                        rooms,
+                        moderation,
+                    )
+                    .await?
                 }
 
-                FromClientMessage::Id { id: new_id } => {
-                    received_id(websocket, character_states, new_id, id, signal_sender).await?
+                return Ok(());
+            }
+
+            FromClientMessage::Spectate { pin, name } => {
+                if table_pin_accepted(websocket, pin, session, pins, moderation).await? {
+                    session.set_name(name);
+                    spectate(websocket, session, signal_sender, moderation).await?
+                }
+
+                return Ok(());
+            }
+
+            FromClientMessage::JoinRoom { campaign } => {
+                return join_room(
+                    websocket,
+                    campaign,
+                    session,
+                    internal_message_receiver,
+                    signal_sender,
+                    rooms,
+                )
+                .await;
+            }
+
+            FromClientMessage::UploadCampaigns {
+                identity,
+                passphrase,
+                campaigns,
+                modified,
+                last_synced,
+                force,
+            } => {
+                if let Some(message) = pin_lockout(PinKind::SyncPassphrase, session, moderation) {
+                    return send(websocket, ToClientMessage::Error { message }).await;
                 }
 
-                FromClientMessage::CharacterUpdated { data } => {
-                    character_updated(data, internal_message_broadcaster, *id, character_states)
-                        .await
+                let outcome = sync::upload(
+                    rooms.read().await.storage(),
+                    identity,
+                    passphrase,
+                    campaigns,
+                    modified,
+                    last_synced,
+                    force,
+                )
+                .await;
+
+                return send(
+                    websocket,
+                    match outcome {
+                        Ok(UploadOutcome::Uploaded) => {
+                            ToClientMessage::CampaignsUploaded { modified }
+                        }
+                        Ok(UploadOutcome::Conflict { server_modified }) => {
+                            ToClientMessage::SyncConflict { server_modified }
+                        }
+                        Err(e) => ToClientMessage::Error {
+                            message: sync_failed(e, session, moderation),
+                        },
+                    },
+                )
+                .await;
+            }
+
+            FromClientMessage::DownloadCampaigns {
+                identity,
+                passphrase,
+            } => {
+                if let Some(message) = pin_lockout(PinKind::SyncPassphrase, session, moderation) {
+                    return send(websocket, ToClientMessage::Error { message }).await;
                 }
+
+                return send(
+                    websocket,
+                    match sync::download(rooms.read().await.storage(), identity, passphrase).await {
+                        Ok((campaigns, modified)) => ToClientMessage::CampaignsDownloaded {
+                            campaigns,
+                            modified,
+                        },
+                        Err(e) => ToClientMessage::Error {
+                            message: sync_failed(e, session, moderation),
+                        },
+                    },
+                )
+                .await;
             }
+
+            _ => {}
         }
 
-        Message::Close(_) => {
-            let id = match id {
-                Some(v) => v,
-                None => return Ok(()),
-            };
+        // Everything else happens in a room
+        let room = match &session.room {
+            Some(v) => v.clone(),
+            None => return Ok(()),
+        };
+
+        let internal_message_broadcaster = &room.broadcaster;
+        let game_state = &room.state;
+
+        // Messages without an id were dropped before the handshake
+        let id = session.id.unwrap();
+
+        match msg {
+            FromClientMessage::RequestId { .. }
+            | FromClientMessage::Id { .. }
+            | FromClientMessage::Spectate { .. }
+            | FromClientMessage::JoinRoom { .. }
+            | FromClientMessage::UploadCampaigns { .. }
+            | FromClientMessage::DownloadCampaigns { .. } => unreachable!(), // The handshake, joining rooms & syncing are handled above
+
+            FromClientMessage::ClaimGm { pin } => {
+                claim_gm(
+                    websocket,
+                    pin,
+                    session,
+                    game_state,
+                    pins,
+                    moderation,
+                    signal_sender,
+                )
+                .await?
+            }
+
+            FromClientMessage::CharacterUpdated { data, visibility } => {
+                character_updated(
+                    data,
+                    visibility,
+                    internal_message_broadcaster,
+                    session,
+                    game_state,
+                )
+                .await
+            }
+
+            FromClientMessage::SetVisibility {
+                character,
+                visibility,
+            } => {
+                set_visibility(
+                    character,
+                    visibility,
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await
+            }
+
+            FromClientMessage::Reveal { character } => {
+                set_visibility(
+                    character,
+                    Visibility::Revealed,
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await
+            }
+
+            FromClientMessage::StartCombat {} => {
+                let mut state = game_state.write().await;
+
+                let outcome = state.start_combat();
+                state.record(GameEvent::CombatStarted);
+
+                turn_changed(outcome, internal_message_broadcaster)
+            }
+
+            FromClientMessage::NextTurn {} => {
+                let mut state = game_state.write().await;
 
-            let mut characters = character_states.write().await;
+                let outcome = state.next_turn();
+                state.record(GameEvent::TurnAdvanced);
 
-            let mut to_remove = Vec::new();
+                turn_changed(outcome, internal_message_broadcaster)
+            }
+
+            FromClientMessage::EndCombat {} => {
+                let mut state = game_state.write().await;
+
+                let outcome = state.end_combat();
+                state.record(GameEvent::CombatEnded);
+
+                turn_changed(outcome, internal_message_broadcaster)
+            }
+
+            FromClientMessage::AddCondition {
+                character,
+                condition,
+            } => {
+                add_condition(
+                    character,
+                    condition,
+                    session,
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await
+            }
+
+            FromClientMessage::RemoveCondition {
+                character,
+                condition,
+            } => {
+                remove_condition(
+                    character,
+                    condition,
+                    session,
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await
+            }
+
+            FromClientMessage::ApplyDamage {
+                targets,
+                amount,
+                damage_type,
+            } => {
+                apply_to_targets(
+                    &targets,
+                    id,
+                    |name, data| apply_damage(name, data, amount, damage_type.as_deref()),
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await;
+            }
+
+            FromClientMessage::ApplyHealing { targets, amount } => {
+                apply_to_targets(
+                    &targets,
+                    id,
+                    |name, data| apply_healing(name, data, amount),
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await;
+            }
+
+            FromClientMessage::AreaEffect {
+                damage,
+                damage_type,
+                save,
+                dc,
+                targets,
+            } => {
+                area_effect(
+                    websocket,
+                    damage,
+                    damage_type,
+                    save,
+                    dc,
+                    targets,
+                    id,
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await?
+            }
+
+            FromClientMessage::SaveTemplate { template } => {
+                // Templates with bad HP would only fail once the GM tries to spawn them
+                if Dice::parse(&template.hp).is_none() {
+                    return send(
+                        websocket,
+                        ToClientMessage::Error {
+                            message: format!("`{}` isn't a valid dice expression", template.hp),
+                        },
+                    )
+                    .await;
+                }
+
+                let mut state = game_state.write().await;
 
-            for (key, (character_id, _)) in characters.iter() {
-                if character_id == id {
-                    to_remove.push(key.clone());
+                state
+                    .templates
+                    .insert(template.name.clone(), template.clone());
+                state.record(GameEvent::TemplateSaved { template });
+            }
+
+            FromClientMessage::DeleteTemplate { name } => {
+                let mut state = game_state.write().await;
+
+                if state.templates.remove(&name).is_some() {
+                    state.record(GameEvent::TemplateDeleted { name });
                 }
             }
 
-            for key in to_remove {
-                characters.remove(&key);
+            FromClientMessage::ListTemplates {} => list_templates(websocket, game_state).await?,
+
+            FromClientMessage::SpawnFromTemplate {
+                template,
+                count,
+                add_to_initiative,
+                visibility,
+            } => {
+                spawn_from_template(
+                    websocket,
+                    template,
+                    count,
+                    add_to_initiative,
+                    visibility.unwrap_or(Visibility::Hidden),
+                    session.id,
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await?
+            }
+
+            FromClientMessage::GetHistory { character } => {
+                get_history(websocket, character, session, game_state).await?
+            }
+
+            FromClientMessage::UndoTo { character, version } => {
+                undo_to(
+                    websocket,
+                    character,
+                    version,
+                    session,
+                    internal_message_broadcaster,
+                    game_state,
+                )
+                .await?
             }
         }
 
-        _ => {}
+        // Anything in the room might've changed, the saver waits for things to settle down before saving
+        room.changed.notify();
     }
 
     Ok(())
@@ -85,57 +454,375 @@ pub(super) async fn on_message(
 
 async fn requested_id(
     websocket: &mut WebSocketStream<Upgraded>,
-    character_states: &Arc<RwLock<HashMap<String, (u32, String)>>>,
-    id: &mut Option<u32>,
+    session: &mut Session,
     signal_sender: &UnboundedSender<ServerMessage>,
+    rooms: &Arc<RwLock<Rooms>>,
+    moderation: &Mutex<Moderation>,
 ) -> Result<(), Error> {
-    let id_value = rand::random();
-
-    id_assigned(websocket, character_states, id_value, id, signal_sender).await?;
+    let (id, token) = rooms.write().await.issue_player();
 
-    send(websocket, ToClientMessage::Id { id: id_value }).await?;
+    id_assigned(id, session, signal_sender, moderation);
 
-    Ok(())
+    send(websocket, ToClientMessage::Id { id, token }).await
 }
 
+/// Let a player back in as who they were before, as long as they have the token that came with their id
 async fn received_id(
     websocket: &mut WebSocketStream<Upgraded>,
-    character_states: &Arc<RwLock<HashMap<String, (u32, String)>>>,
     new_id: u32,
-    id: &mut Option<u32>,
+    token: &str,
+    session: &mut Session,
     signal_sender: &UnboundedSender<ServerMessage>,
+    rooms: &Arc<RwLock<Rooms>>,
+    moderation: &Mutex<Moderation>,
 ) -> Result<(), Error> {
-    if id.is_some() {
-        return Ok(());
+    if !rooms.read().await.verify_player(new_id, token) {
+        warn!(
+            "Someone tried to reconnect as player {} without their token, giving them a new id",
+            new_id
+        );
+
+        return requested_id(websocket, session, signal_sender, rooms, moderation).await;
     }
 
-    id_assigned(websocket, character_states, new_id, id, signal_sender).await?;
+    id_assigned(new_id, session, signal_sender, moderation);
 
     Ok(())
 }
 
-async fn id_assigned(
-    websocket: &mut WebSocketStream<Upgraded>,
-    character_states: &Arc<RwLock<HashMap<String, (u32, String)>>>,
+fn id_assigned(
     new_id: u32,
-    id: &mut Option<u32>,
+    session: &mut Session,
+    signal_sender: &UnboundedSender<ServerMessage>,
+    moderation: &Mutex<Moderation>,
+) {
+    session.id = Some(new_id);
+
+    // Reconnecting doesn't undo being muted
+    session.muted = moderation.lock().unwrap().is_player_muted(new_id);
+
+    // Everything logged for the connection from now on says which player it's for
+    Span::current().record("player", new_id);
+    info!("Assigned player id {}", new_id);
+
+    // They can't have joined a room yet, so they don't have any characters
+    if let Some(connection) = session.info(Vec::new()) {
+        signal_sender.send(NewConnection { connection }).ok();
+    }
+}
+
+/// Tell the server's GUI about the connection again, after something about it changed
+pub(super) async fn report_connection(
+    session: &Session,
+    signal_sender: &UnboundedSender<ServerMessage>,
+) {
+    let mut characters = match &session.room {
+        Some(room) => room
+            .state
+            .read()
+            .await
+            .characters
+            .iter()
+            .filter(|(_, character)| Some(character.owner) == session.id)
+            .map(|(name, _)| name.clone())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    characters.sort();
+
+    if let Some(connection) = session.info(characters) {
+        signal_sender.send(ConnectionChanged { connection }).ok();
+    }
+}
+
+/// Make the session a spectator, their id is only used to show them in the server's GUI so the client isn't told it
+async fn spectate(
+    websocket: &mut WebSocketStream<Upgraded>,
+    session: &mut Session,
     signal_sender: &UnboundedSender<ServerMessage>,
+    moderation: &Mutex<Moderation>,
 ) -> Result<(), Error> {
-    *id = Some(new_id);
+    session.role = Role::Spectator;
 
-    signal_sender.send(NewConnection { id: id.unwrap() }).ok();
+    id_assigned(rand::random(), session, signal_sender, moderation);
 
-    let characters = character_states.read().await;
+    send(websocket, ToClientMessage::Role { role: session.role }).await
+}
 
-    for (player_id, data) in characters.values() {
-        send(
-            websocket,
-            ToClientMessage::CharacterUpdated {
-                data: data.to_string(),
-                player_id: *player_id,
+/// Move the session into the room for a campaign, and send them everything that's going on in it
+async fn join_room(
+    websocket: &mut WebSocketStream<Upgraded>,
+    campaign: String,
+    session: &mut Session,
+    internal_message_receiver: &mut Option<broadcast::Receiver<InternalMessage>>,
+    signal_sender: &UnboundedSender<ServerMessage>,
+    rooms: &Arc<RwLock<Rooms>>,
+) -> Result<(), Error> {
+    // Joining a room is only allowed after the handshake
+    let id = session.id.unwrap();
+
+    let mut rooms_lock = rooms.write().await;
+
+    if let Some(old_room) = session.room.take() {
+        old_room
+            .state
+            .write()
+            .await
+            .record(GameEvent::PlayerLeft { id });
+
+        let departure = rooms_lock.leave(&old_room.name, id);
+
+        // The GM's characters are the room's NPCs, which are prepared ahead of time & kept
+        if !session.is_gm() {
+            remove_characters_later(Arc::clone(rooms), old_room, id, departure);
+        }
+    }
+
+    let room = rooms_lock.join(&campaign, id);
+
+    signal_sender
+        .send(RoomsChanged {
+            rooms: rooms_lock.summary(),
+        })
+        .ok();
+
+    drop(rooms_lock);
+
+    // Being the GM of one campaign doesn't make you the GM of another
+    if session.is_gm() {
+        session.role = Role::Player;
+    }
+
+    room.state.write().await.record(GameEvent::PlayerJoined {
+        id,
+        role: session.role,
+    });
+
+    *internal_message_receiver = Some(room.broadcaster.subscribe());
+
+    let game_state = Arc::clone(&room.state);
+
+    session.room = Some(room);
+
+    report_connection(session, signal_sender).await;
+
+    send_state(websocket, session, &game_state, false).await
+}
+
+/// Check the PIN a client joined with, telling them if it's wrong
+async fn table_pin_accepted(
+    websocket: &mut WebSocketStream<Upgraded>,
+    pin: Option<u32>,
+    session: &mut Session,
+    pins: &watch::Receiver<Pins>,
+    moderation: &Mutex<Moderation>,
+) -> Result<bool, Error> {
+    let table_pin = pins.borrow().table;
+
+    match table_pin {
+        Some(table_pin) => {
+            check_pin(
+                websocket,
+                pin,
+                table_pin,
+                PinKind::Table,
+                session,
+                moderation,
+            )
+            .await
+        }
+        None => Ok(true),
+    }
+}
+
+/// Check a PIN, telling the client if it's refused
+async fn check_pin(
+    websocket: &mut WebSocketStream<Upgraded>,
+    pin: Option<u32>,
+    expected: u32,
+    kind: PinKind,
+    session: &mut Session,
+    moderation: &Mutex<Moderation>,
+) -> Result<bool, Error> {
+    match pin_refusal(pin, expected, kind, session, moderation) {
+        Some(message) => {
+            send(websocket, ToClientMessage::Error { message }).await?;
+
+            Ok(false)
+        }
+        None => Ok(true),
+    }
+}
+
+/// Why the PIN isn't accepted, None if it is
+///
+/// Connections & addresses that get it wrong too many times have to wait before trying again, even with the right PIN
+fn pin_refusal(
+    pin: Option<u32>,
+    expected: u32,
+    kind: PinKind,
+    session: &mut Session,
+    moderation: &Mutex<Moderation>,
+) -> Option<String> {
+    if let Some(message) = pin_lockout(kind, session, moderation) {
+        return Some(message);
+    }
+
+    let correct = pin == Some(expected);
+
+    moderation
+        .lock()
+        .unwrap()
+        .pin_attempted(session.address.ip(), kind, correct);
+
+    if correct {
+        return None;
+    }
+
+    session.wrong_pins += 1;
+
+    warn!("Wrong {} from {}", kind.name(), session.address.ip());
+
+    Some(format!("Wrong {}", kind.name()))
+}
+
+/// Why the session can't try the PIN right now, if it or its address got it wrong too many times
+fn pin_lockout(kind: PinKind, session: &Session, moderation: &Mutex<Moderation>) -> Option<String> {
+    if session.wrong_pins >= MAX_WRONG_PINS_PER_CONNECTION {
+        return Some("Too many wrong PINs, reconnect to try again".to_owned());
+    }
+
+    let lockout = moderation
+        .lock()
+        .unwrap()
+        .pin_lockout(session.address.ip(), kind)?;
+
+    Some(format!(
+        "Too many wrong {}s, try again in {} seconds",
+        kind.name(),
+        lockout.as_secs() + 1
+    ))
+}
+
+/// What to tell the player when syncing failed, wrong passphrases count towards the address's limit
+///
+/// Right passphrases don't reset the limit, since anyone can make a new identity to get one right
+fn sync_failed(error: SyncError, session: &mut Session, moderation: &Mutex<Moderation>) -> String {
+    if let SyncError::WrongPassphrase = error {
+        session.wrong_pins += 1;
+
+        warn!("Wrong sync passphrase from {}", session.address.ip());
+
+        moderation.lock().unwrap().pin_attempted(
+            session.address.ip(),
+            PinKind::SyncPassphrase,
+            false,
+        );
+    }
+
+    error.message()
+}
+
+/// Make the session the GM if they know the GM PIN
+async fn claim_gm(
+    websocket: &mut WebSocketStream<Upgraded>,
+    pin: u32,
+    session: &mut Session,
+    game_state: &Arc<RwLock<GameState>>,
+    pins: &watch::Receiver<Pins>,
+    moderation: &Mutex<Moderation>,
+    signal_sender: &UnboundedSender<ServerMessage>,
+) -> Result<(), Error> {
+    let gm_pin = pins.borrow().gm;
+
+    if !check_pin(
+        websocket,
+        Some(pin),
+        gm_pin,
+        PinKind::Gm,
+        session,
+        moderation,
+    )
+    .await?
+    {
+        return Ok(());
+    }
+
+    session.role = Role::Gm;
+
+    report_connection(session, signal_sender).await;
+
+    send(websocket, ToClientMessage::Role { role: session.role }).await?;
+
+    // The GM can see characters that were hidden from them as a player
+    send_state(websocket, session, game_state, false).await
+}
+
+/// Send everything the session is allowed to see about the game
+///
+/// With `overwrite`, the client replaces its own copies of its characters too, for when it might've missed changes the server made to them
+pub(super) async fn send_state(
+    websocket: &mut WebSocketStream<Upgraded>,
+    session: &Session,
+    game_state: &Arc<RwLock<GameState>>,
+    overwrite: bool,
+) -> Result<(), Error> {
+    let state = game_state.read().await;
+
+    let mut characters = Vec::new();
+    let mut messages = Vec::new();
+
+    for (name, character) in state.characters.iter() {
+        let data = match character.data_for(session) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        characters.push(SentCharacter {
+            data,
+            player_id: character.owner,
+        });
+
+        if !character.conditions.is_empty() {
+            messages.push(ToClientMessage::ConditionsUpdated {
+                character: name.clone(),
+                conditions: character.conditions.clone(),
+            });
+        }
+    }
+
+    if let Some(character) = state.initiative.current_turn() {
+        let visible = state
+            .characters
+            .get(character)
+            .map(|v| v.visibility_for(session))
+            != Some(Visibility::Hidden);
+
+        messages.push(ToClientMessage::TurnChanged {
+            round: state.initiative.round,
+            character: if visible {
+                Some(character.to_owned())
+            } else {
+                None
             },
-        )
-        .await?;
+        });
+    }
+
+    drop(state);
+
+    // The whole list is sent at once, so characters removed while the client wasn't keeping up are removed for them too
+    send(
+        websocket,
+        ToClientMessage::Characters {
+            characters,
+            overwrite,
+        },
+    )
+    .await?;
+
+    for message in messages {
+        send(websocket, message).await?;
     }
 
     Ok(())
@@ -143,9 +830,10 @@ async fn id_assigned(
 
 async fn character_updated(
     data: String,
+    visibility: Option<Visibility>,
     internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
-    id: Option<u32>,
-    character_states: &Arc<RwLock<HashMap<String, (u32, String)>>>,
+    session: &Session,
+    game_state: &Arc<RwLock<GameState>>,
 ) {
     let name = match serde_json::from_str(&data) {
         Ok(serde_json::Value::Object(obj)) => {
@@ -160,42 +848,576 @@ async fn character_updated(
         _ => return,
     };
 
-    println!("id: {:?}", &id);
+    let id = match session.id {
+        Some(v) => v,
+        None => return,
+    };
+
+    trace!("Character {} updated by player {}", name, id);
+
+    let mut state = game_state.write().await;
+
+    let event = match state.characters.get_mut(&name) {
+        Some(character) => {
+            // Players can't take over characters that belong to someone else
+            if !session.can_edit(character.owner) {
+                return;
+            }
+
+            // The GM editing someone else's character shouldn't take it away from them
+            if !session.is_gm() {
+                character.owner = id;
+            }
+
+            let event = GameEvent::CharacterChanged {
+                name: name.clone(),
+                owner: character.owner,
+                data: data.clone(),
+                author: id,
+            };
+
+            character.set_data(data, id);
+
+            event
+        }
+
+        None => {
+            let visibility = visibility.unwrap_or_default();
+
+            state.characters.insert(
+                name.clone(),
+                CharacterState::new(id, data.clone(), visibility),
+            );
+
+            GameEvent::CharacterAdded {
+                name: name.clone(),
+                owner: id,
+                data,
+                visibility,
+            }
+        }
+    };
+
+    state.record(event);
+
+    drop(state);
 
+    // The character is stored before telling everyone about it, since its visibility is checked when it gets sent
     internal_message_broadcaster
         .send(InternalMessage::CharacterUpdated {
-            character_data: data.clone(),
-            player_id: match id {
-                Some(v) => v,
-                None => return,
-            },
+            name,
+            overwrite: false,
+        })
+        .ok();
+}
+
+/// Change who can see a character, players who can't see it anymore are told to remove it
+async fn set_visibility(
+    character: String,
+    visibility: Visibility,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+    game_state: &Arc<RwLock<GameState>>,
+) {
+    let mut state = game_state.write().await;
+
+    let character_state = match state.characters.get_mut(&character) {
+        Some(v) => v,
+        None => return,
+    };
+
+    let owner = character_state.owner;
+    let previous = std::mem::replace(&mut character_state.visibility, visibility);
+
+    state.record(GameEvent::VisibilityChanged {
+        name: character.clone(),
+        visibility,
+    });
+
+    drop(state);
+
+    internal_message_broadcaster
+        .send(InternalMessage::VisibilityChanged {
+            name: character,
+            owner,
+            previous,
+        })
+        .ok();
+}
+
+/// Tell everyone whose turn it is, and which conditions expired because of the turn changing
+fn turn_changed(
+    outcome: TurnOutcome,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+) {
+    internal_message_broadcaster
+        .send(InternalMessage::TurnChanged {
+            round: outcome.round,
+            character: outcome.character,
         })
         .ok();
 
-    let mut states = character_states.write().await;
-    states.insert(name, (id.unwrap(), data));
-    drop(states);
+    for (character, condition) in outcome.expired {
+        internal_message_broadcaster
+            .send(InternalMessage::ConditionExpired {
+                character,
+                condition,
+            })
+            .ok();
+    }
 }
 
-pub(super) async fn received_internal_message(
-    msg: InternalMessage,
+async fn add_condition(
+    character: String,
+    mut condition: Condition,
+    session: &Session,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+    game_state: &Arc<RwLock<GameState>>,
+) {
+    let mut state = game_state.write().await;
+
+    let character_state = match state.characters.get_mut(&character) {
+        Some(v) if session.can_edit(v.owner) => v,
+        _ => return,
+    };
+
+    condition.reset();
+
+    // Adding a condition that's already there refreshes its duration instead of stacking it
+    character_state
+        .conditions
+        .retain(|v| v.name != condition.name);
+    character_state.conditions.push(condition);
+
+    let conditions = character_state.conditions.clone();
+
+    state.record(GameEvent::ConditionsChanged {
+        name: character.clone(),
+        conditions: conditions.clone(),
+    });
+
+    internal_message_broadcaster
+        .send(InternalMessage::ConditionsUpdated {
+            character,
+            conditions,
+        })
+        .ok();
+}
+
+/// Damage or heal each of the targets, and tell everyone what happened
+async fn apply_to_targets(
+    targets: &[String],
+    author: u32,
+    mut apply: impl FnMut(&str, &mut serde_json::Map<String, serde_json::Value>) -> DamageLogEntry,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+    game_state: &Arc<RwLock<GameState>>,
+) {
+    let mut state = game_state.write().await;
+
+    for name in targets {
+        let character = match state.characters.get_mut(name) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let entry = match character.edit_data(author, |data| apply(name, data)) {
+            Some(v) => v,
+            None => continue,
+        };
+
+        let event = GameEvent::CharacterChanged {
+            name: name.clone(),
+            owner: character.owner,
+            data: character.data.clone(),
+            author,
+        };
+
+        state.record(event);
+        state.record(GameEvent::Damaged {
+            entry: entry.clone(),
+        });
+
+        internal_message_broadcaster
+            .send(InternalMessage::CharacterUpdated {
+                name: name.clone(),
+                overwrite: true,
+            })
+            .ok();
+
+        internal_message_broadcaster
+            .send(InternalMessage::DamageApplied {
+                entry: entry.clone(),
+            })
+            .ok();
+
+        state.damage_log.push(entry);
+    }
+}
+
+/// Roll damage once, then have every target roll a saving throw to take half damage
+#[allow(clippy::too_many_arguments)]
+async fn area_effect(
     websocket: &mut WebSocketStream<Upgraded>,
+    damage: String,
+    damage_type: Option<String>,
+    save: Ability,
+    dc: i64,
+    targets: Vec<String>,
+    author: u32,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+    game_state: &Arc<RwLock<GameState>>,
 ) -> Result<(), Error> {
-    match msg {
-        InternalMessage::CharacterUpdated {
-            character_data,
-            player_id,
-        } => {
-            send(
+    let dice = match Dice::parse(&damage) {
+        Some(v) => v,
+        None => {
+            return send(
                 websocket,
-                ToClientMessage::CharacterUpdated {
-                    data: character_data,
-                    player_id,
+                ToClientMessage::Error {
+                    message: format!("`{}` isn't a valid dice expression", damage),
                 },
             )
-            .await?;
+            .await
         }
+    };
+
+    let rolled = dice.roll();
+    let dealt = u32::try_from(rolled.max(0)).unwrap_or(u32::MAX);
+
+    let mut rolls = vec![RollLogEntry::new(
+        "Area effect damage".to_owned(),
+        &damage,
+        rolled,
+    )];
+    let mut results = Vec::new();
+
+    apply_to_targets(
+        &targets,
+        author,
+        |name, data| {
+            let (target, roll) =
+                resolve_target(name, data, dealt, damage_type.as_deref(), save, dc);
+
+            rolls.push(roll);
+
+            let entry = target.damage.clone();
+            results.push(target);
+            entry
+        },
+        internal_message_broadcaster,
+        game_state,
+    )
+    .await;
+
+    let mut state = game_state.write().await;
+
+    for entry in rolls {
+        state.roll_log.push(entry.clone());
+        state.record(GameEvent::Rolled { entry });
+    }
+
+    drop(state);
+
+    send(
+        websocket,
+        ToClientMessage::AreaEffectResolved {
+            damage: rolled,
+            targets: results,
+        },
+    )
+    .await
+}
+
+async fn list_templates(
+    websocket: &mut WebSocketStream<Upgraded>,
+    game_state: &Arc<RwLock<GameState>>,
+) -> Result<(), Error> {
+    let mut templates = game_state
+        .read()
+        .await
+        .templates
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    templates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    send(websocket, ToClientMessage::Templates { templates }).await
+}
+
+/// Make a pack of NPCs from a template, numbering them after any that already exist
+#[allow(clippy::too_many_arguments)]
+async fn spawn_from_template(
+    websocket: &mut WebSocketStream<Upgraded>,
+    template: String,
+    count: u32,
+    add_to_initiative: bool,
+    visibility: Visibility,
+    id: Option<u32>,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+    game_state: &Arc<RwLock<GameState>>,
+) -> Result<(), Error> {
+    let id = match id {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    if count > MAX_SPAWN {
+        return send(
+            websocket,
+            ToClientMessage::Error {
+                message: format!("Only {} NPCs can be spawned at once", MAX_SPAWN),
+            },
+        )
+        .await;
+    }
+
+    let mut state = game_state.write().await;
+
+    let template = match state.templates.get(&template) {
+        Some(v) => v.clone(),
+        None => {
+            drop(state);
+
+            return send(
+                websocket,
+                ToClientMessage::Error {
+                    message: format!("There's no template called {}", template),
+                },
+            )
+            .await;
+        }
+    };
+
+    let mut number = 1;
+
+    for _ in 0..count {
+        while state.characters.contains_key(&template.name_of(number)) {
+            number += 1;
+        }
+
+        let npc = match template.spawn(template.name_of(number), id) {
+            Some(v) => v,
+            None => {
+                drop(state);
+
+                return send(
+                    websocket,
+                    ToClientMessage::Error {
+                        message: format!("`{}` isn't a valid dice expression", template.hp),
+                    },
+                )
+                .await;
+            }
+        };
+
+        state.characters.insert(
+            npc.name.clone(),
+            CharacterState::new(id, npc.data.clone(), visibility),
+        );
+        state.record(GameEvent::CharacterAdded {
+            name: npc.name.clone(),
+            owner: id,
+            data: npc.data,
+            visibility,
+        });
+
+        for entry in npc.rolls {
+            state.roll_log.push(entry.clone());
+            state.record(GameEvent::Rolled { entry });
+        }
+
+        if add_to_initiative {
+            state.join_combat(&npc.name);
+            state.record(GameEvent::JoinedCombat {
+                name: npc.name.clone(),
+            });
+        }
+
+        internal_message_broadcaster
+            .send(InternalMessage::CharacterUpdated {
+                name: npc.name,
+                overwrite: false,
+            })
+            .ok();
     }
 
     Ok(())
 }
+
+async fn remove_condition(
+    character: String,
+    condition: String,
+    session: &Session,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+    game_state: &Arc<RwLock<GameState>>,
+) {
+    let mut state = game_state.write().await;
+
+    let character_state = match state.characters.get_mut(&character) {
+        Some(v) if session.can_edit(v.owner) => v,
+        _ => return,
+    };
+
+    character_state.conditions.retain(|v| v.name != condition);
+
+    let conditions = character_state.conditions.clone();
+
+    state.record(GameEvent::ConditionsChanged {
+        name: character.clone(),
+        conditions: conditions.clone(),
+    });
+
+    internal_message_broadcaster
+        .send(InternalMessage::ConditionsUpdated {
+            character,
+            conditions,
+        })
+        .ok();
+}
+
+pub(super) async fn received_internal_message(
+    msg: InternalMessage,
+    websocket: &mut WebSocketStream<Upgraded>,
+    session: &Session,
+) -> Result<(), Error> {
+    let game_state = match &session.room {
+        Some(v) => &v.state,
+        None => return Ok(()),
+    };
+
+    let state = game_state.read().await;
+
+    let message = message_for(msg, session, &state);
+
+    drop(state);
+
+    match message {
+        Some(message) => send(websocket, message).await,
+        None => Ok(()),
+    }
+}
+
+/// Send the session a character's recent versions, if they're allowed to edit it
+async fn get_history(
+    websocket: &mut WebSocketStream<Upgraded>,
+    character: String,
+    session: &Session,
+    game_state: &Arc<RwLock<GameState>>,
+) -> Result<(), Error> {
+    let state = game_state.read().await;
+
+    let versions = match state.characters.get(&character) {
+        Some(v) if session.can_edit(v.owner) => v.history.iter().rev().cloned().collect(),
+        _ => return Ok(()),
+    };
+
+    drop(state);
+
+    send(
+        websocket,
+        ToClientMessage::History {
+            character,
+            versions,
+        },
+    )
+    .await
+}
+
+/// Put a character back the way it was in an old version, if the session is allowed to edit it
+async fn undo_to(
+    websocket: &mut WebSocketStream<Upgraded>,
+    character: String,
+    version: u32,
+    session: &Session,
+    internal_message_broadcaster: &broadcast::Sender<InternalMessage>,
+    game_state: &Arc<RwLock<GameState>>,
+) -> Result<(), Error> {
+    let id = match session.id {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    let mut state = game_state.write().await;
+
+    let character_state = match state.characters.get_mut(&character) {
+        Some(v) if session.can_edit(v.owner) => v,
+        _ => return Ok(()),
+    };
+
+    if character_state.undo_to(version, id).is_none() {
+        drop(state);
+
+        return send(
+            websocket,
+            ToClientMessage::Error {
+                message: format!("Version {} of {} is too old to undo to", version, character),
+            },
+        )
+        .await;
+    }
+
+    let event = GameEvent::CharacterChanged {
+        name: character.clone(),
+        owner: character_state.owner,
+        data: character_state.data.clone(),
+        author: id,
+    };
+
+    state.record(event);
+
+    drop(state);
+
+    // The owner's copy is out of date now too
+    internal_message_broadcaster
+        .send(InternalMessage::CharacterUpdated {
+            name: character,
+            overwrite: true,
+        })
+        .ok();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        Session::new(1, "127.0.0.1:1234".parse().unwrap())
+    }
+
+    #[test]
+    fn wrong_pins_are_refused() {
+        let moderation = Mutex::new(Moderation::default());
+        let mut session = session();
+
+        let refusal = pin_refusal(Some(1234), 4321, PinKind::Table, &mut session, &moderation);
+        assert_eq!(refusal, Some(format!("Wrong {}", PinKind::Table.name())));
+
+        assert!(pin_refusal(None, 4321, PinKind::Table, &mut session, &moderation).is_some());
+        assert_eq!(session.wrong_pins, 2);
+
+        assert_eq!(
+            pin_refusal(Some(4321), 4321, PinKind::Table, &mut session, &moderation),
+            None
+        );
+    }
+
+    #[test]
+    fn guessing_pins_gets_locked_out() {
+        let moderation = Mutex::new(Moderation::default());
+        let mut session = session();
+
+        while pin_lockout(PinKind::Gm, &session, &moderation).is_none() {
+            pin_refusal(Some(1), 4321, PinKind::Gm, &mut session, &moderation);
+        }
+
+        // Even the right PIN is refused until the wait is over
+        assert!(pin_refusal(Some(4321), 4321, PinKind::Gm, &mut session, &moderation).is_some());
+
+        // Other PINs can still be tried
+        assert_eq!(
+            pin_refusal(Some(5), 5, PinKind::Table, &mut session, &moderation),
+            None
+        );
+    }
+}
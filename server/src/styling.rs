@@ -51,3 +51,22 @@ impl text_input::StyleSheet for TextInput {
         Color::from_rgb8(64, 128, 255)
     }
 }
+
+/// A health bar that goes from green to red as the character gets hurt
+pub struct HpBar(pub f32);
+
+impl progress_bar::StyleSheet for HpBar {
+    fn style(&self) -> progress_bar::Style {
+        let color = match self.0 {
+            v if v > 0.5 => Color::from_rgb8(64, 192, 64),
+            v if v > 0.25 => Color::from_rgb8(255, 200, 64),
+            _ => Color::from_rgb8(255, 96, 96),
+        };
+
+        progress_bar::Style {
+            background: Background::Color(Color::from_rgb8(64, 64, 64)),
+            bar: Background::Color(color),
+            border_radius: BORDER_RADIUS / 2.0,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::hash::{Hash, Hasher};
+
+use iced::{futures::stream::BoxStream, Subscription};
+use iced_native::subscription::Recipe;
+use server::{Server, ServerMessage};
+use tokio::sync::broadcast;
+
+use crate::{gui::Message, logging::LogEntry};
+
+/// Create a subscription to everything that happens on the server
+pub fn subscribe(server: &Server) -> Subscription<Message> {
+    Subscription::from_recipe(ServerSubscription {
+        id: server.id(),
+        events: server.events(),
+    })
+}
+
+/// Create a subscription to everything that's logged
+pub fn subscribe_to_logs(logs: &broadcast::Sender<LogEntry>) -> Subscription<Message> {
+    Subscription::from_recipe(LogSubscription {
+        logs: logs.subscribe(),
+    })
+}
+
+/// A subscription to the server's events
+struct ServerSubscription {
+    id: u32,
+    events: BoxStream<'static, ServerMessage>,
+}
+
+/// A Recipe is iced's way of letting you tell the GUI about changes to background tasks
+impl<H: Hasher, I> Recipe<H, I> for ServerSubscription {
+    type Output = Message;
+
+    /// A unique identifier for this recipe instance
+    fn hash(&self, state: &mut H) {
+        struct Marker;
+        std::any::TypeId::of::<Marker>().hash(state);
+
+        self.id.hash(state);
+    }
+
+    /// Stream messages to the GUI, iced will await futures in the stream and send the message to the GUI when they resolve.
+    ///
+    /// Status changes are sent separately, since the GUI handles them on their own
+    fn stream(self: Box<Self>, _input: BoxStream<I>) -> BoxStream<Self::Output> {
+        use iced::futures::StreamExt;
+
+        Box::pin(self.events.map(|message| match message {
+            ServerMessage::Status(status) => Message::ServerStatus(status),
+            message => Message::ServerMessage(message),
+        }))
+    }
+}
+
+/// A subscription to the log
+struct LogSubscription {
+    logs: broadcast::Receiver<LogEntry>,
+}
+
+impl<H: Hasher, I> Recipe<H, I> for LogSubscription {
+    type Output = Message;
+
+    /// There's only ever one log, so every log subscription is the same
+    fn hash(&self, state: &mut H) {
+        struct Marker;
+        std::any::TypeId::of::<Marker>().hash(state);
+    }
+
+    fn stream(self: Box<Self>, _input: BoxStream<I>) -> BoxStream<Self::Output> {
+        Box::pin(iced::futures::stream::unfold(
+            self.logs,
+            |mut logs| async move {
+                loop {
+                    match logs.recv().await {
+                        Ok(entry) => return Some((Message::Log(entry), logs)),
+
+                        // The GUI fell behind, so some messages are skipped
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))
+    }
+}
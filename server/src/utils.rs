@@ -1,12 +1,27 @@
+use std::fs::{self, File};
 use std::future::Future;
-use std::net::{IpAddr, UdpSocket};
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the data is kept if it was set with `set_data_dir`
+static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
 
 /// Get the location where the application can store data
 pub fn get_data_dir() -> PathBuf {
-    dirs::data_dir()
-        .expect("You're running an unsupported operating system")
-        .join("dnd-stuff")
+    match DATA_DIR.get() {
+        Some(v) => v.clone(),
+        None => dirs::data_dir()
+            .expect("You're running an unsupported operating system")
+            .join("dnd-stuff"),
+    }
+}
+
+/// Store data somewhere other than the usual place, this has to be done before anything reads or writes data
+pub fn set_data_dir(dir: PathBuf) {
+    DATA_DIR.set(dir).ok();
 }
 
 /// Get the server's local ip address that the server can be accessed from
@@ -30,6 +45,12 @@ pub fn get_local_ip() -> Option<IpAddr> {
     }
 }
 
+/// Find a free port a little after `port` on the address, for when `port` is already taken
+pub fn next_free_port(address: IpAddr, port: u16) -> Option<u16> {
+    (port.checked_add(1)?..=port.saturating_add(100))
+        .find(|&candidate| TcpListener::bind((address, candidate)).is_ok())
+}
+
 /// Await the value in an option
 ///
 /// If the value is Some, await what's inside and return the value awaited
@@ -40,3 +61,35 @@ pub async fn await_option<T>(v: Option<impl Future<Output = T>>) -> Option<T> {
         None => None,
     }
 }
+
+/// The amount of seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs())
+        .unwrap_or(0)
+}
+
+/// Write a file so that it either has the new contents or the old contents, even if the app crashes halfway through
+///
+/// The contents are written to a temporary file next to it first, which then replaces the real file. Every write gets its own temporary file, so writing the same file twice at once can't mix the two together
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let temp_path = path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+
+    let written = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+
+    match written.and_then(|_| fs::rename(&temp_path, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            fs::remove_file(&temp_path).ok();
+            Err(e)
+        }
+    }
+}